@@ -1,75 +1,254 @@
+use crate::chain_params::ChainParams;
 use crate::errors::*;
+use crate::hash::hash256;
 use crate::merkle::*;
-use crate::serializer;
+use crate::pow::{self, U256};
+use crate::serializer::{self, Decodable, Encodable, Reader};
+use crate::transaction::{sum_amounts, CoinBaseTransaction, Transaction};
 use crate::utxo_set::UtxoSet;
-use crate::wallet;
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
 use std::collections::HashSet;
 use std::time::SystemTime;
 
 const BLOCK_VERSION: i32 = 1;
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+const MEDIAN_TIME_SPAN: usize = 11;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone)]
 pub struct Block {
     version: i32,
     previous_block_header_hash: Vec<u8>,
     merkle_root: Vec<u8>,
     timestamp: u64,
-    nonce: usize,
+    bits: u32,
+    nonce: u64,
     transactions: Vec<Vec<u8>>,
 }
 
 impl Block {
-    pub fn new(previous_block_header_hash: Vec<u8>, transactions: Vec<Vec<u8>>) -> Self {
+    pub fn new(previous_block_header_hash: Vec<u8>, transactions: Vec<Vec<u8>>, bits: u32) -> Self {
         let merkle_root = get_merkle_root(&transactions);
         Self {
             version: BLOCK_VERSION,
             previous_block_header_hash,
             merkle_root,
             timestamp: Self::calculate_timestamp(),
+            bits,
             nonce: 0,
             transactions,
         }
     }
 
+    // The genesis block is fixed per network, its coinbase pays to an all-zero pubkey hash
+    // nobody holds a key for.
+    pub fn genesis(params: &ChainParams) -> Result<Self, RitCoinErrror<'static>> {
+        let coinbase_transaction: Transaction =
+            CoinBaseTransaction::new(&[0; 20], 0, params.initial_block_subsidy);
+        let transactions = vec![serializer::serialize(&coinbase_transaction)];
+        Ok(Self {
+            version: BLOCK_VERSION,
+            previous_block_header_hash: vec![0; 32],
+            merkle_root: get_merkle_root(&transactions),
+            timestamp: params.genesis_timestamp,
+            bits: params.pow_limit_bits,
+            nonce: params.genesis_nonce,
+            transactions,
+        })
+    }
+
     pub fn validate_transactions(
         &self,
         utxo_set: &UtxoSet,
+        previous_blocks: &[Block],
+        params: &ChainParams,
         verify_only: bool,
     ) -> Result<(), RitCoinErrror<'static>> {
-        let pub_keys = self.pub_keys_from_txins()?;
-        let pk_hashes: Vec<_> = pub_keys
-            .iter()
-            .map(|pub_key| wallet::pk_hash_from_public_key(pub_key))
-            .collect();
-        let utxos: Vec<_> = pk_hashes
-            .iter()
-            .map(|pk_hash| utxo_set.by_pkhash(pk_hash))
-            .flatten()
-            .collect();
-        // Do not validate coinbase transaction
-        for (i, transaction) in self.transactions.iter().enumerate() {
+        let block_height = previous_blocks.len() as u32;
+        let mut tx_ids = HashSet::new();
+        let mut spent_outpoints = HashSet::new();
+        for (i, transaction) in self.get_deserialized_transactions()?.iter().enumerate() {
+            if !tx_ids.insert(transaction.tx_id()) {
+                return Err(RitCoinErrror::from("Block contains duplicate transactions"));
+            }
+            transaction.check_output_amounts(params.max_money)?;
+            transaction.check_locks(utxo_set, previous_blocks)?;
+            // Do not validate coinbase transaction
             if i != 0 {
-                let transaction = serializer::deserialize(transaction)?;
+                // Every output may be spent only once across the whole block, transactions
+                // are validated against the same utxo snapshot
+                for input in transaction.get_tx_in() {
+                    if !spent_outpoints.insert(input.get_previous_output()) {
+                        return Err(RitCoinErrror::from(
+                            "Block spends the same output more than once",
+                        ));
+                    }
+                }
+                transaction.check_coinbase_maturity(
+                    utxo_set,
+                    block_height,
+                    params.coinbase_maturity,
+                )?;
                 if verify_only {
-                    transaction.verify(&utxos)?
+                    transaction.verify(utxo_set)?
                 } else {
-                    transaction.validate(&utxos)?;
+                    transaction.validate(utxo_set)?;
                 }
             }
         }
         Ok(())
     }
 
+    pub fn validate_header(&self, median_time_past: u64) -> Result<(), RitCoinErrror<'static>> {
+        if self.version != BLOCK_VERSION {
+            return Err(RitCoinErrror::from("Unsupported block version"));
+        }
+        if U256::from_be_bytes(&self.hash()) > self.get_target() {
+            return Err(RitCoinErrror::from(
+                "Block hash does not meet the difficulty target",
+            ));
+        }
+        if self.timestamp <= median_time_past {
+            return Err(RitCoinErrror::from(
+                "Block timestamp is not after the median time of previous blocks",
+            ));
+        }
+        if self.timestamp > Self::calculate_timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(RitCoinErrror::from(
+                "Block timestamp is too far in the future",
+            ));
+        }
+        if get_merkle_root(&self.transactions) != self.merkle_root {
+            return Err(RitCoinErrror::from(
+                "Merkle root does not match block transactions",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate_coinbase(
+        &self,
+        block_height: u32,
+        max_amount: u64,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let transactions = self.get_deserialized_transactions()?;
+        let coinbase = match transactions.first() {
+            Some(coinbase) if coinbase.is_coinbase() => coinbase,
+            _ => {
+                return Err(RitCoinErrror::from(
+                    "First block transaction is not a coinbase transaction",
+                ))
+            }
+        };
+        if transactions.iter().skip(1).any(Transaction::is_coinbase) {
+            return Err(RitCoinErrror::from(
+                "Block contains more than one coinbase transaction",
+            ));
+        }
+        if coinbase.get_coinbase_height() != Some(block_height) {
+            return Err(RitCoinErrror::from(
+                "Coinbase height does not match block height",
+            ));
+        }
+        // Output amounts are summed without wrapping, so outputs adding up past u64::MAX
+        // cannot pass for a small amount
+        match coinbase.get_outputs_sum() {
+            Ok(amount) if amount <= max_amount => Ok(()),
+            _ => Err(RitCoinErrror::from("Coinbase amount exceeds block reward")),
+        }
+    }
+
+    pub fn get_fees(&self, utxo_set: &UtxoSet) -> Result<u64, RitCoinErrror<'static>> {
+        let mut fees = vec![];
+        for transaction in self.get_deserialized_transactions()?.iter().skip(1) {
+            fees.push(transaction.get_fee(utxo_set)?);
+        }
+        sum_amounts(fees)
+    }
+
+    pub fn median_time_past(previous_blocks: &[Block]) -> u64 {
+        let mut timestamps: Vec<_> = previous_blocks
+            .iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|block| block.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).cloned().unwrap_or(0)
+    }
+
     pub fn hash(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.input(self.version.to_string());
-        hasher.input(&self.previous_block_header_hash);
-        hasher.input(&self.merkle_root);
-        hasher.input(self.timestamp.to_string());
-        hasher.input(self.nonce.to_string());
-        hasher.result().to_vec()
+        let mut header = vec![];
+        self.encode_header(&mut header);
+        hash256(&header)
+    }
+
+    fn encode_header(&self, buffer: &mut Vec<u8>) {
+        serializer::write_i32(buffer, self.version);
+        buffer.extend_from_slice(&self.previous_block_header_hash);
+        buffer.extend_from_slice(&self.merkle_root);
+        serializer::write_u64(buffer, self.timestamp);
+        serializer::write_u32(buffer, self.bits);
+        serializer::write_u64(buffer, self.nonce);
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_target(&self) -> U256 {
+        U256::from_compact(self.bits)
+    }
+
+    pub fn next_bits(previous_blocks: &[Block], params: &ChainParams) -> u32 {
+        let last_block = match previous_blocks.last() {
+            Some(last_block) => last_block,
+            None => return params.pow_limit_bits,
+        };
+        let position_in_interval = previous_blocks.len() % params.retarget_interval;
+        if params.pow_no_retargeting || position_in_interval != 0 {
+            return last_block.bits;
+        }
+        let first_block = &previous_blocks[previous_blocks.len() - params.retarget_interval];
+        pow::retarget(
+            params,
+            last_block.bits,
+            last_block.timestamp.saturating_sub(first_block.timestamp),
+        )
+    }
+
+    pub fn work(&self) -> Result<U256, RitCoinErrror<'static>> {
+        pow::block_work(self.get_target())
+    }
+
+    // Checks that the headers link up from the network genesis block, carry the expected
+    // difficulty bits and meet their targets, then returns their cumulative work.
+    pub fn validate_headers(
+        blocks: &[Block],
+        params: &ChainParams,
+    ) -> Result<U256, RitCoinErrror<'static>> {
+        let mut chain_work = U256::zero();
+        for (height, block) in blocks.iter().enumerate() {
+            let linked = match height {
+                0 => hex::encode(block.hash()) == params.genesis_hash,
+                _ => block.get_previous_hash() == blocks[height - 1].hash().as_slice(),
+            };
+            if !linked {
+                return Err(RitCoinErrror::from("Block headers do not form a chain"));
+            }
+            if block.bits != Self::next_bits(&blocks[..height], params) {
+                return Err(RitCoinErrror::from(
+                    "Block difficulty bits do not match the expected target",
+                ));
+            }
+            if U256::from_be_bytes(&block.hash()) > block.get_target() {
+                return Err(RitCoinErrror::from(
+                    "Block hash does not meet the difficulty target",
+                ));
+            }
+            chain_work = chain_work + block.work()?;
+        }
+        Ok(chain_work)
     }
 
     pub fn get_previous_hash(&self) -> &[u8] {
@@ -80,27 +259,24 @@ impl Block {
         &self.transactions
     }
 
-    pub fn increment_nonce(&mut self) {
-        self.nonce += 1;
+    pub fn get_deserialized_transactions(
+        &self,
+    ) -> Result<Vec<Transaction>, RitCoinErrror<'static>> {
+        let mut transactions = Vec::with_capacity(self.transactions.len());
+        for transaction in &self.transactions {
+            transactions.push(serializer::deserialize(transaction)?);
+        }
+        Ok(transactions)
     }
 
-    pub fn update_timestamp(&mut self) {
-        self.timestamp = Self::calculate_timestamp();
+    pub fn increment_nonce(&mut self) {
+        self.nonce += 1;
     }
 
-    pub fn pub_keys_from_txins(&self) -> Result<Vec<Vec<u8>>, RitCoinErrror<'static>> {
-        let mut pub_keys_set = HashSet::new();
-        for (i, transaction) in self.transactions.iter().enumerate() {
-            if i != 0 {
-                let transaction = serializer::deserialize(transaction)?;
-                let pub_keys = transaction.get_pub_keys_from_inputs();
-                pub_keys_set = pub_keys_set.union(&pub_keys).cloned().collect();
-            }
-        }
-        Ok(pub_keys_set
-            .into_iter()
-            .map(|pub_key_set| pub_key_set.to_vec())
-            .collect())
+    pub fn update_timestamp(&mut self, median_time_past: u64) {
+        self.timestamp = Self::calculate_timestamp()
+            .max(self.timestamp)
+            .max(median_time_past + 1);
     }
 
     fn calculate_timestamp() -> u64 {
@@ -110,3 +286,227 @@ impl Block {
             .as_secs()
     }
 }
+
+impl Encodable for Block {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.encode_header(buffer);
+        serializer::write_var_int(buffer, self.transactions.len() as u64);
+        for transaction in &self.transactions {
+            buffer.extend_from_slice(transaction);
+        }
+    }
+}
+
+impl Decodable for Block {
+    fn decode(reader: &mut Reader) -> Result<Self, RitCoinErrror<'static>> {
+        let version = reader.read_i32()?;
+        let previous_block_header_hash = reader.read_hash()?;
+        let merkle_root = reader.read_hash()?;
+        let timestamp = reader.read_u64()?;
+        let bits = reader.read_u32()?;
+        let nonce = reader.read_u64()?;
+        let transactions_count = reader.read_count()?;
+        let mut transactions = Vec::with_capacity(transactions_count);
+        for _ in 0..transactions_count {
+            transactions.push(serializer::serialize(&Transaction::decode(reader)?));
+        }
+        Ok(Self {
+            version,
+            previous_block_header_hash,
+            merkle_root,
+            timestamp,
+            bits,
+            nonce,
+            transactions,
+        })
+    }
+}
+
+// Blocks travel between nodes as the hex string of their consensus encoding.
+impl Serialize for Block {
+    fn serialize<S: Serializer>(&self, serde_serializer: S) -> Result<S::Ok, S::Error> {
+        serde_serializer.serialize_str(&hex::encode(serializer::serialize(self)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Block {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = hex::decode(String::deserialize(deserializer)?).map_err(de::Error::custom)?;
+        serializer::deserialize(&encoded).map_err(|e| de::Error::custom(format!("{:?}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{error_message, mine_block};
+    use crate::transaction::{Input, Output, SIGHASH_ALL};
+    use crate::wallet;
+
+    const PRIVATE_KEY: [u8; 32] = [1; 32];
+
+    fn pkhash() -> Vec<u8> {
+        wallet::pk_hash_from_public_key(&wallet::private_key_to_public_key(&PRIVATE_KEY).unwrap())
+    }
+
+    fn coinbase(block_height: u32, amount: u64) -> Transaction {
+        CoinBaseTransaction::new(&pkhash(), block_height, amount)
+    }
+
+    // A utxo set holding one output of 10 paid to the test key.
+    fn funded_utxo_set() -> UtxoSet {
+        let funding = Transaction::new(vec![], vec![Output::create(10, &pkhash())]);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_unspent(&funding, 0);
+        utxo_set
+    }
+
+    fn spend(utxo_set: &UtxoSet, receiver_pkhash: &[u8]) -> Transaction {
+        let utxo = utxo_set.by_pkhash(&pkhash())[0];
+        let mut transaction = Transaction::new(
+            vec![Input::create(utxo)],
+            vec![Output::create(10, receiver_pkhash)],
+        );
+        transaction.sign(&PRIVATE_KEY, SIGHASH_ALL).unwrap();
+        transaction
+    }
+
+    fn block_of(transactions: &[Transaction]) -> Block {
+        let transactions = transactions.iter().map(serializer::serialize).collect();
+        Block::new(vec![0; serializer::HASH_SIZE], transactions, 0x207f_ffff)
+    }
+
+    fn validate_transactions(
+        transactions: &[Transaction],
+        utxo_set: &UtxoSet,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let params = ChainParams::regtest();
+        let previous_blocks = vec![Block::genesis(&params).unwrap()];
+        block_of(transactions).validate_transactions(utxo_set, &previous_blocks, &params, false)
+    }
+
+    #[test]
+    fn block_may_not_spend_an_output_twice() {
+        let utxo_set = funded_utxo_set();
+        let first = spend(&utxo_set, &[5; 20]);
+        let second = spend(&utxo_set, &[6; 20]);
+        assert!(validate_transactions(&[coinbase(1, 1), first.clone()], &utxo_set).is_ok());
+        assert!(validate_transactions(&[coinbase(1, 1), second.clone()], &utxo_set).is_ok());
+        assert_eq!(
+            error_message(validate_transactions(
+                &[coinbase(1, 1), first, second],
+                &utxo_set
+            )),
+            "Block spends the same output more than once"
+        );
+    }
+
+    #[test]
+    fn block_may_not_repeat_a_transaction() {
+        let utxo_set = funded_utxo_set();
+        let transaction = spend(&utxo_set, &[5; 20]);
+        assert_eq!(
+            error_message(validate_transactions(
+                &[coinbase(1, 1), transaction.clone(), transaction],
+                &utxo_set
+            )),
+            "Block contains duplicate transactions"
+        );
+        assert_eq!(
+            error_message(validate_transactions(
+                &[coinbase(1, 1), coinbase(1, 1)],
+                &utxo_set
+            )),
+            "Block contains duplicate transactions"
+        );
+    }
+
+    fn grind(block: &mut Block) {
+        while U256::from_be_bytes(&block.hash()) > block.get_target() {
+            block.increment_nonce();
+        }
+    }
+
+    fn mined_block(transactions: &[Transaction]) -> Block {
+        mine_block(&[], transactions, &ChainParams::regtest())
+    }
+
+    #[test]
+    fn header_checks_reject_invalid_headers() {
+        let block = mined_block(&[coinbase(0, 1)]);
+        assert!(block.validate_header(block.timestamp - 1).is_ok());
+
+        let mut changed = block.clone();
+        changed.version = BLOCK_VERSION + 1;
+        grind(&mut changed);
+        assert_eq!(
+            error_message(changed.validate_header(0)),
+            "Unsupported block version"
+        );
+
+        let mut changed = block.clone();
+        changed.bits = 0x0300_0001;
+        assert_eq!(
+            error_message(changed.validate_header(0)),
+            "Block hash does not meet the difficulty target"
+        );
+
+        assert_eq!(
+            error_message(block.validate_header(block.timestamp)),
+            "Block timestamp is not after the median time of previous blocks"
+        );
+
+        let mut changed = block.clone();
+        changed.timestamp = Block::calculate_timestamp() + MAX_FUTURE_BLOCK_TIME + 60;
+        grind(&mut changed);
+        assert_eq!(
+            error_message(changed.validate_header(0)),
+            "Block timestamp is too far in the future"
+        );
+
+        let mut changed = block;
+        changed
+            .transactions
+            .push(serializer::serialize(&coinbase(1, 1)));
+        assert_eq!(
+            error_message(changed.validate_header(0)),
+            "Merkle root does not match block transactions"
+        );
+    }
+
+    #[test]
+    fn coinbase_checks_reject_invalid_coinbases() {
+        assert!(mined_block(&[coinbase(1, 50)])
+            .validate_coinbase(1, 50)
+            .is_ok());
+        let spend = spend(&funded_utxo_set(), &[5; 20]);
+        let cases = [
+            (
+                vec![],
+                "First block transaction is not a coinbase transaction",
+            ),
+            (
+                vec![spend.clone(), coinbase(1, 50)],
+                "First block transaction is not a coinbase transaction",
+            ),
+            (
+                vec![coinbase(1, 50), spend, coinbase(2, 50)],
+                "Block contains more than one coinbase transaction",
+            ),
+            (
+                vec![coinbase(2, 50)],
+                "Coinbase height does not match block height",
+            ),
+            (
+                vec![coinbase(1, 51)],
+                "Coinbase amount exceeds block reward",
+            ),
+        ];
+        for (transactions, message) in cases.iter() {
+            assert_eq!(
+                error_message(mined_block(transactions).validate_coinbase(1, 50)),
+                *message
+            );
+        }
+    }
+}
@@ -0,0 +1,524 @@
+use crate::block::Block;
+use crate::errors::*;
+use crate::pow::U256;
+use crate::serializer;
+use crate::utxo_set::BlockUndo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+pub const BLOCKS_DIR: &str = "blocks";
+const INDEX_FILE: &str = "index.dat";
+const MAX_BLOCK_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct DiskLocation {
+    file: u32,
+    offset: u64,
+    length: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockLocation {
+    block: DiskLocation,
+    undo: Option<DiskLocation>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct BlockIndex {
+    by_hash: HashMap<Vec<u8>, BlockLocation>,
+    by_height: Vec<Vec<u8>>,
+    last_file: u32,
+}
+
+// Blocks are appended to <data dir>/blocks/blkNNNNN.dat and their undo data to
+// revNNNNN.dat as length-prefixed records, the index maps every stored block hash
+// to its records and keeps the hashes of the active chain ordered by height.
+#[derive(Clone, Default)]
+pub struct BlockStore {
+    dir: PathBuf,
+    index: BlockIndex,
+}
+
+impl BlockStore {
+    pub fn open(dir: &str) -> Result<Self, RitCoinErrror<'static>> {
+        fs::create_dir_all(dir)?;
+        let mut store = Self {
+            dir: PathBuf::from(dir),
+            index: BlockIndex::default(),
+        };
+        let index_path = store.dir.join(INDEX_FILE);
+        match fs::read(index_path) {
+            Ok(index) => store.index = bincode::deserialize(&index)?,
+            Err(_) => store.reindex()?,
+        }
+        store.trim_files()?;
+        Ok(store)
+    }
+
+    fn file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    fn undo_file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("rev{:05}.dat", file))
+    }
+
+    fn save_index(&self) -> Result<(), RitCoinErrror<'static>> {
+        let tmp_path = self.dir.join(INDEX_FILE.to_owned() + ".tmp");
+        fs::write(&tmp_path, bincode::serialize(&self.index)?)?;
+        fs::rename(tmp_path, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    fn write_record(path: &Path, data: &[u8]) -> Result<u64, RitCoinErrror<'static>> {
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        let offset = file.seek(SeekFrom::End(0))? + 4;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        Ok(offset)
+    }
+
+    fn read_record(
+        path: &Path,
+        location: &DiskLocation,
+    ) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut data = vec![0; location.length as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_records(path: &Path) -> Result<Vec<(u64, Vec<u8>)>, RitCoinErrror<'static>> {
+        let data = fs::read(path)?;
+        let mut records = vec![];
+        let mut offset = 0;
+        while offset + 4 <= data.len() {
+            let mut length = [0; 4];
+            length.copy_from_slice(&data[offset..offset + 4]);
+            let length = u32::from_le_bytes(length) as usize;
+            if offset + 4 + length > data.len() {
+                break;
+            }
+            records.push((
+                (offset + 4) as u64,
+                data[offset + 4..offset + 4 + length].to_vec(),
+            ));
+            offset += 4 + length;
+        }
+        Ok(records)
+    }
+
+    // Rebuilds the index by scanning the data files, used when the index file is missing.
+    // Undo records are matched to their blocks by hash, a block connected more than once
+    // keeps its latest undo record. Like a torn length prefix, a record that does not
+    // decode ends the scan of its file, and opening the store then cuts the file back to
+    // the records before it.
+    fn reindex(&mut self) -> Result<(), RitCoinErrror<'static>> {
+        let mut blocks = vec![];
+        let mut file_number = 0;
+        while self.file_path(file_number).exists() {
+            for (offset, record) in Self::read_records(&self.file_path(file_number))? {
+                let block: Block = match serializer::deserialize(&record) {
+                    Ok(block) => block,
+                    Err(_) => break,
+                };
+                let location = BlockLocation {
+                    block: DiskLocation {
+                        file: file_number,
+                        offset,
+                        length: record.len() as u32,
+                    },
+                    undo: None,
+                };
+                self.index.by_hash.insert(block.hash(), location);
+                blocks.push(block);
+            }
+            if self.undo_file_path(file_number).exists() {
+                for (offset, record) in Self::read_records(&self.undo_file_path(file_number))? {
+                    let (hash, _): (Vec<u8>, BlockUndo) = match bincode::deserialize(&record) {
+                        Ok(undo) => undo,
+                        Err(_) => break,
+                    };
+                    if let Some(location) = self.index.by_hash.get_mut(&hash) {
+                        location.undo = Some(DiskLocation {
+                            file: file_number,
+                            offset,
+                            length: record.len() as u32,
+                        });
+                    }
+                }
+            }
+            self.index.last_file = file_number;
+            file_number += 1;
+        }
+        self.index.by_height = Self::best_chain(&blocks);
+        if !self.index.by_hash.is_empty() {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    // Cuts every data and undo file back to the end of the last record the index refers
+    // to. A write torn by a crash leaves a partial record at the end of its file, records
+    // appended after it would be read as part of it when reindexing and be lost.
+    fn trim_files(&self) -> Result<(), RitCoinErrror<'static>> {
+        let mut ends = HashMap::new();
+        for location in self.index.by_hash.values() {
+            let mut records = vec![(self.file_path(location.block.file), &location.block)];
+            if let Some(undo) = &location.undo {
+                records.push((self.undo_file_path(undo.file), undo));
+            }
+            for (path, record) in records {
+                let end = ends.entry(path).or_insert(0);
+                *end = (*end).max(record.offset + record.length as u64);
+            }
+        }
+        let mut file_number = 0;
+        while self.file_path(file_number).exists() || self.undo_file_path(file_number).exists() {
+            for path in [
+                self.file_path(file_number),
+                self.undo_file_path(file_number),
+            ]
+            .iter()
+            {
+                let end = ends.get(path).copied().unwrap_or(0);
+                if path.exists() && fs::metadata(path)?.len() > end {
+                    OpenOptions::new().write(true).open(path)?.set_len(end)?;
+                }
+            }
+            file_number += 1;
+        }
+        Ok(())
+    }
+
+    // Picks the stored branch with the most cumulative work, on equal work the branch
+    // stored first wins. Blocks whose ancestors are missing are left out.
+    fn best_chain(blocks: &[Block]) -> Vec<Vec<u8>> {
+        let headers: HashMap<_, _> = blocks
+            .iter()
+            .filter_map(|block| {
+                Some((
+                    block.hash(),
+                    (block.get_previous_hash(), block.work().ok()?),
+                ))
+            })
+            .collect();
+        let mut chain_work: HashMap<&[u8], U256> = HashMap::new();
+        let mut best: Option<(&[u8], U256)> = None;
+        for block in blocks {
+            let hash = block.hash();
+            let mut branch = vec![];
+            let mut current = hash.as_slice();
+            let ancestor_work = loop {
+                if let Some(work) = chain_work.get(current) {
+                    break Some(*work);
+                }
+                match headers.get_key_value(current) {
+                    Some((stored_hash, (previous, work))) => {
+                        branch.push((stored_hash.as_slice(), *work));
+                        current = previous;
+                    }
+                    None if current.iter().all(|byte| *byte == 0) => break Some(U256::zero()),
+                    None => break None,
+                }
+            };
+            if let Some(mut work) = ancestor_work {
+                for (branch_hash, block_work) in branch.into_iter().rev() {
+                    work = work + block_work;
+                    chain_work.insert(branch_hash, work);
+                }
+                let is_best = match best {
+                    Some((_, best_work)) => work > best_work,
+                    None => true,
+                };
+                if is_best {
+                    best = headers
+                        .get_key_value(hash.as_slice())
+                        .map(|(stored_hash, _)| (stored_hash.as_slice(), work));
+                }
+            }
+        }
+        let mut chain = vec![];
+        let mut current = best.map(|(hash, _)| hash);
+        while let Some((hash, (previous, _))) = current.and_then(|hash| headers.get_key_value(hash))
+        {
+            chain.push(hash.clone());
+            current = Some(previous);
+        }
+        chain.reverse();
+        chain
+    }
+
+    // The whole index is rewritten after every block, which costs time linear in the
+    // chain length per block. Fine for chains of this size, a larger chain would need
+    // the index written incrementally.
+    pub fn append(&mut self, block: &Block) -> Result<(), RitCoinErrror<'static>> {
+        let hash = block.hash();
+        if !self.index.by_hash.contains_key(&hash) {
+            let data = serializer::serialize(block);
+            let mut path = self.file_path(self.index.last_file);
+            if path.exists() && fs::metadata(&path)?.len() + data.len() as u64 > MAX_BLOCK_FILE_SIZE
+            {
+                self.index.last_file += 1;
+                path = self.file_path(self.index.last_file);
+            }
+            let location = BlockLocation {
+                block: DiskLocation {
+                    file: self.index.last_file,
+                    offset: Self::write_record(&path, &data)?,
+                    length: data.len() as u32,
+                },
+                undo: None,
+            };
+            self.index.by_hash.insert(hash.clone(), location);
+        }
+        self.index.by_height.push(hash);
+        self.save_index()
+    }
+
+    pub fn write_undo(
+        &mut self,
+        hash: &[u8],
+        undo: &BlockUndo,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let file = match self.index.by_hash.get(hash) {
+            Some(location) => location.block.file,
+            None => return Err(RitCoinErrror::from("Block not found in block index")),
+        };
+        // Undo records carry their block hash so reindexing can match them up again
+        let data = bincode::serialize(&(hash, undo))?;
+        let undo_location = DiskLocation {
+            file,
+            offset: Self::write_record(&self.undo_file_path(file), &data)?,
+            length: data.len() as u32,
+        };
+        if let Some(location) = self.index.by_hash.get_mut(hash) {
+            location.undo = Some(undo_location);
+        }
+        self.save_index()
+    }
+
+    pub fn read_undo(&self, hash: &[u8]) -> Result<BlockUndo, RitCoinErrror<'static>> {
+        match self
+            .index
+            .by_hash
+            .get(hash)
+            .and_then(|location| location.undo.as_ref())
+        {
+            Some(undo) => {
+                let (_, undo): (Vec<u8>, BlockUndo) = bincode::deserialize(&Self::read_record(
+                    &self.undo_file_path(undo.file),
+                    undo,
+                )?)?;
+                Ok(undo)
+            }
+            None => Err(RitCoinErrror::from("Undo data not found for block")),
+        }
+    }
+
+    pub fn truncate(&mut self, height: usize) -> Result<(), RitCoinErrror<'static>> {
+        self.index.by_height.truncate(height);
+        self.save_index()
+    }
+
+    pub fn get_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, RitCoinErrror<'static>> {
+        match self.index.by_hash.get(hash) {
+            Some(location) => Ok(Some(serializer::deserialize(&Self::read_record(
+                &self.file_path(location.block.file),
+                &location.block,
+            )?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn load_blocks(&self) -> Result<Vec<Block>, RitCoinErrror<'static>> {
+        let mut blocks = Vec::with_capacity(self.index.by_height.len());
+        for hash in &self.index.by_height {
+            match self.get_by_hash(hash)? {
+                Some(block) => blocks.push(block),
+                None => return Err(RitCoinErrror::from("Block index refers to missing block")),
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params::ChainParams;
+    use crate::test_utils::{error_message, mine_block, test_params};
+    use crate::transaction::{CoinBaseTransaction, Output, Transaction};
+    use crate::utxo_set::Utxo;
+
+    fn open_store(params: &ChainParams) -> BlockStore {
+        BlockStore::open(&params.data_path(BLOCKS_DIR)).unwrap()
+    }
+
+    // Mines a block after blocks, pkhash tells blocks at the same height apart.
+    fn next_block(blocks: &[Block], pkhash: u8, params: &ChainParams) -> Block {
+        let coinbase: Transaction = CoinBaseTransaction::new(&[pkhash; 20], blocks.len() as u32, 1);
+        mine_block(blocks, &[coinbase], params)
+    }
+
+    fn chain(length: usize, params: &ChainParams) -> Vec<Block> {
+        let mut blocks = vec![];
+        while blocks.len() < length {
+            let block = next_block(&blocks, 1, params);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<Vec<u8>> {
+        blocks.iter().map(Block::hash).collect()
+    }
+
+    fn undo(amount: u64) -> BlockUndo {
+        let output = Output::create(amount, &[2; 20]);
+        vec![vec![], vec![Utxo::new(vec![3; 32], 0, output, 1, false)]]
+    }
+
+    fn append_all(store: &mut BlockStore, blocks: &[Block]) {
+        for (amount, block) in blocks.iter().enumerate() {
+            store.append(block).unwrap();
+            store
+                .write_undo(&block.hash(), &undo(amount as u64))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn appended_blocks_are_found_by_hash() {
+        let params = test_params("store_append");
+        let blocks = chain(3, &params);
+        let mut store = open_store(&params);
+        append_all(&mut store, &blocks);
+        for block in &blocks {
+            let stored = store.get_by_hash(&block.hash()).unwrap().unwrap();
+            assert_eq!(serializer::serialize(&stored), serializer::serialize(block));
+        }
+        assert!(store.get_by_hash(&[0; 32]).unwrap().is_none());
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+        assert_eq!(store.read_undo(&blocks[2].hash()).unwrap(), undo(2));
+        assert_eq!(
+            error_message(store.read_undo(&[0; 32])),
+            "Undo data not found for block"
+        );
+    }
+
+    #[test]
+    fn reopened_store_loads_the_index() {
+        let params = test_params("store_reopen");
+        let blocks = chain(3, &params);
+        let mut store = open_store(&params);
+        append_all(&mut store, &blocks);
+        store.truncate(2).unwrap();
+
+        let store = open_store(&params);
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks[..2]));
+        assert_eq!(store.read_undo(&blocks[1].hash()).unwrap(), undo(1));
+    }
+
+    #[test]
+    fn reindex_rebuilds_a_deleted_index() {
+        let params = test_params("store_reindex");
+        let blocks = chain(3, &params);
+        let mut store = open_store(&params);
+        append_all(&mut store, &blocks);
+        // A reconnected block keeps its latest undo record
+        store.write_undo(&blocks[1].hash(), &undo(7)).unwrap();
+        fs::remove_file(store.dir.join(INDEX_FILE)).unwrap();
+
+        let store = open_store(&params);
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+        assert_eq!(store.read_undo(&blocks[0].hash()).unwrap(), undo(0));
+        assert_eq!(store.read_undo(&blocks[1].hash()).unwrap(), undo(7));
+        assert!(store.dir.join(INDEX_FILE).exists());
+    }
+
+    #[test]
+    fn reindex_stops_at_a_torn_or_corrupt_record() {
+        let params = test_params("store_torn");
+        let blocks = chain(2, &params);
+        let mut store = open_store(&params);
+        append_all(&mut store, &blocks);
+        let files = [store.file_path(0), store.undo_file_path(0)];
+        let contents: Vec<_> = files.iter().map(|path| fs::read(path).unwrap()).collect();
+        // A length prefix promising more bytes than were written, then a complete
+        // record that does not decode
+        let tails: [&[u8]; 2] = [&[100, 0, 0, 0, 1, 2, 3], &[3, 0, 0, 0, 1, 2, 3]];
+        for tail in tails.iter() {
+            for (path, content) in files.iter().zip(&contents) {
+                let mut data = content.clone();
+                data.extend_from_slice(tail);
+                fs::write(path, data).unwrap();
+            }
+            let _ = fs::remove_file(store.dir.join(INDEX_FILE));
+            let store = open_store(&params);
+            assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+            assert_eq!(store.read_undo(&blocks[1].hash()).unwrap(), undo(1));
+        }
+    }
+
+    #[test]
+    fn blocks_appended_after_a_torn_record_survive_a_reindex() {
+        let params = test_params("store_torn_append");
+        let blocks = chain(4, &params);
+        let torn_tail: &[u8] = &[100, 0, 0, 0, 1, 2, 3];
+        let mut store = open_store(&params);
+        append_all(&mut store, &blocks[..2]);
+        // With the index kept and with the index lost at the crash
+        for (crash, lose_index) in [(2, false), (3, true)].iter() {
+            for path in [store.file_path(0), store.undo_file_path(0)].iter() {
+                let mut data = fs::read(path).unwrap();
+                data.extend_from_slice(torn_tail);
+                fs::write(path, data).unwrap();
+            }
+            if *lose_index {
+                fs::remove_file(store.dir.join(INDEX_FILE)).unwrap();
+            }
+            store = open_store(&params);
+            append_all(&mut store, &blocks[*crash..*crash + 1]);
+        }
+        fs::remove_file(store.dir.join(INDEX_FILE)).unwrap();
+
+        let store = open_store(&params);
+        assert_eq!(hashes(&store.load_blocks().unwrap()), hashes(&blocks));
+        assert_eq!(store.read_undo(&blocks[3].hash()).unwrap(), undo(0));
+    }
+
+    #[test]
+    fn best_chain_follows_the_most_work() {
+        let params = test_params("store_best_chain");
+        let blocks = chain(2, &params);
+        let mut fork = blocks[..1].to_vec();
+        for _ in 0..2 {
+            let block = next_block(&fork, 2, &params);
+            fork.push(block);
+        }
+        let mut stored = blocks.clone();
+        stored.extend_from_slice(&fork[1..]);
+        assert_eq!(BlockStore::best_chain(&stored), hashes(&fork));
+
+        // On equal work the branch stored first wins
+        let mut stored = blocks.clone();
+        stored.push(fork[1].clone());
+        assert_eq!(BlockStore::best_chain(&stored), hashes(&blocks));
+        stored.swap(1, 2);
+        assert_eq!(BlockStore::best_chain(&stored), hashes(&fork[..2]));
+
+        // Blocks whose ancestors are missing are left out
+        assert_eq!(BlockStore::best_chain(&fork[1..]), Vec::<Vec<u8>>::new());
+        let orphan = next_block(&fork[..2], 2, &params);
+        let mut stored = blocks.clone();
+        stored.push(orphan);
+        assert_eq!(BlockStore::best_chain(&stored), hashes(&blocks));
+    }
+}
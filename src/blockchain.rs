@@ -1,113 +1,317 @@
 use crate::block::Block;
+use crate::block_store::{BlockStore, BLOCKS_DIR};
+use crate::chain_params::ChainParams;
 use crate::errors::*;
 use crate::pending_pool;
+use crate::pow::U256;
 use crate::serializer;
-use crate::server::{CHAIN_RESOURCE, DEFAULT_NODE_ADDRESS};
+use crate::server::{CHAIN_RESOURCE, CHAIN_WORK_RESOURCE};
 use crate::transaction::*;
 use crate::utxo_set::*;
 use crate::wallet;
 use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::net::SocketAddrV4;
 use std::time::Instant;
 
-const DEFAULT_DIFFICULTY: usize = 2;
-const MINER_KEY_PATH: &str = "data/miner_key.txt";
+const MINER_KEY_FILE: &str = "miner_key.txt";
 const BLOCK_TRANSACTIONS_COUNT: usize = 3;
-const DEFAULT_COINBASE_AMOUNT: u64 = 50;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct BlockChain {
     blocks: Vec<Block>,
     nodes: Vec<SocketAddrV4>,
     utxo: UtxoSet,
+    #[serde(skip)]
+    store: BlockStore,
+    #[serde(skip)]
+    params: ChainParams,
 }
 
 impl BlockChain {
-    pub fn new() -> Self {
-        if let Ok(existing_blockchain) = Self::exist(DEFAULT_NODE_ADDRESS) {
-            existing_blockchain
-        } else {
-            Self {
-                blocks: vec![],
-                nodes: vec![],
-                utxo: UtxoSet::new(),
+    pub fn new(params: ChainParams) -> Result<Self, RitCoinErrror<'static>> {
+        let store = BlockStore::open(&params.data_path(BLOCKS_DIR))?;
+        let mut blockchain = Self {
+            blocks: store.load_blocks()?,
+            nodes: vec![],
+            utxo: UtxoSet::load(&params.data_path(UTXO_SET_FILE))
+                .unwrap_or_else(|_| UtxoSet::new()),
+            store,
+            params,
+        };
+        if blockchain.utxo.get_tip() != blockchain.tip_hash().as_slice() {
+            blockchain.rebuild_utxos()?;
+        }
+        if blockchain.blocks.is_empty() {
+            let default_node_address = blockchain.params.default_node_address();
+            if let Ok(existing_blockchain) = Self::exist(&default_node_address, &blockchain.params)
+            {
+                match existing_blockchain.verify_chain() {
+                    Ok(()) => {
+                        for block in existing_blockchain.blocks {
+                            blockchain.connect_block(block)?;
+                        }
+                        blockchain.remove_invalid_pending()?;
+                        blockchain.nodes = existing_blockchain.nodes;
+                    }
+                    Err(e) => println!("Chain of {} rejected: {:?}", default_node_address, e),
+                }
             }
         }
+        if blockchain.blocks.is_empty() {
+            let genesis_block = Block::genesis(&blockchain.params)?;
+            blockchain.connect_block(genesis_block)?;
+        }
+        Ok(blockchain)
+    }
+
+    fn tip_hash(&self) -> Vec<u8> {
+        self.blocks.last().map(Block::hash).unwrap_or_default()
+    }
+
+    fn rebuild_utxos(&mut self) -> Result<(), RitCoinErrror<'static>> {
+        let mut utxo = UtxoSet::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            utxo.recalculate_utxos(&block.get_deserialized_transactions()?, height as u32);
+        }
+        utxo.set_tip(self.tip_hash());
+        self.utxo = utxo;
+        self.utxo.save(&self.params.data_path(UTXO_SET_FILE))
     }
 
     pub fn get_utxos_ref(&self) -> &UtxoSet {
         &self.utxo
     }
 
-    pub fn exist(address: &str) -> Result<Self, RitCoinErrror<'static>> {
+    pub fn exist(address: &str, params: &ChainParams) -> Result<Self, RitCoinErrror<'static>> {
         let client = Client::new();
         let chain_url = address.to_owned() + CHAIN_RESOURCE;
         let mut res = client.post(&chain_url).send()?;
         if res.status() == StatusCode::OK {
-            Ok(res.json()?)
+            let mut blockchain: Self = res.json()?;
+            blockchain.params = params.clone();
+            Ok(blockchain)
         } else {
             Err(RitCoinErrror::from(res.text()?))
         }
     }
 
     pub fn mine(&mut self) -> Result<(), RitCoinErrror<'static>> {
-        if self.blocks.is_empty() {
-            let genesis_block = Self::genesis_block(self.len() as u32)?;
-            self.start_mine(genesis_block)
-        } else {
-            let private_key = wallet::wif_to_private_key_from_file(MINER_KEY_PATH)?;
-            let public_key = wallet::private_key_to_public_key(&private_key)?;
-            let pk_hash = wallet::pk_hash_from_public_key(&public_key);
-            let mut pending_transactions =
-                pending_pool::get_last_transactions(Some(BLOCK_TRANSACTIONS_COUNT))?;
-            let coinbase_transaction =
-                CoinBaseTransaction::new(&pk_hash, self.len() as u32, DEFAULT_COINBASE_AMOUNT);
-            let coinbase_transaction_serialized = serializer::serialize(&coinbase_transaction)?;
-            pending_transactions.insert(0, coinbase_transaction_serialized);
-            let block = Block::new(
-                self.blocks[self.blocks.len() - 1].hash(),
-                pending_transactions,
-            );
-            block.validate_transactions(&self.utxo, false)?;
-            self.start_mine(block)?;
-            pending_pool::delete_last_n_transactions(BLOCK_TRANSACTIONS_COUNT)?;
-            Ok(())
+        let private_key = wallet::wif_to_private_key_from_file(
+            &self.params.data_path(MINER_KEY_FILE),
+            &self.params,
+        )?;
+        let public_key = wallet::private_key_to_public_key(&private_key)?;
+        let pk_hash = wallet::pk_hash_from_public_key(&public_key);
+        let mut pending_transactions = vec![];
+        let mut fees = 0;
+        let mut spent = HashSet::new();
+        for pending_transaction in pending_pool::get_last_transactions(None, &self.params)? {
+            if pending_transactions.len() == BLOCK_TRANSACTIONS_COUNT {
+                break;
+            }
+            // Transactions that cannot go into this block are skipped instead of failing it
+            let fee = serializer::deserialize(&pending_transaction).and_then(|transaction| {
+                pending_pool::check_transaction(
+                    &transaction,
+                    &self.utxo,
+                    &self.blocks,
+                    &self.params,
+                )?;
+                let fee = transaction.get_fee(&self.utxo)?;
+                let inputs: Vec<_> = transaction
+                    .get_tx_in()
+                    .iter()
+                    .map(|input| input.get_previous_output().clone())
+                    .collect();
+                if inputs.iter().any(|outpoint| spent.contains(outpoint)) {
+                    return Err(RitCoinErrror::from("Transaction conflicts with the block"));
+                }
+                spent.extend(inputs);
+                Ok(fee)
+            });
+            if let Ok(fee) = fee {
+                fees = sum_amounts([fees, fee])?;
+                pending_transactions.push(pending_transaction);
+            }
         }
+        let block_reward =
+            sum_amounts([Self::block_subsidy(self.len() as u32, &self.params), fees])?;
+        let coinbase_transaction: Transaction =
+            CoinBaseTransaction::new(&pk_hash, self.len() as u32, block_reward);
+        let coinbase_transaction_serialized = serializer::serialize(&coinbase_transaction);
+        pending_transactions.insert(0, coinbase_transaction_serialized);
+        let block = Block::new(
+            self.tip_hash(),
+            pending_transactions,
+            Block::next_bits(&self.blocks, &self.params),
+        );
+        block.validate_transactions(&self.utxo, &self.blocks, &self.params, false)?;
+        self.start_mine(block)?;
+        self.remove_invalid_pending()
     }
 
     pub fn start_mine(&mut self, mut block: Block) -> Result<(), RitCoinErrror<'static>> {
+        let median_time_past = Block::median_time_past(&self.blocks);
+        block.update_timestamp(median_time_past);
         let mut start = Instant::now();
-        while !block.hash().starts_with(&[0; DEFAULT_DIFFICULTY]) {
+        let target = block.get_target();
+        while U256::from_be_bytes(&block.hash()) > target {
             block.increment_nonce();
             if start.elapsed().as_secs() == 2 {
-                block.update_timestamp();
+                block.update_timestamp(median_time_past);
                 start = Instant::now();
             }
         }
-        let mut deserialised_transactions = vec![];
-        for serialized_transaction in block.get_transactions() {
-            let deserialised_transaction = serializer::deserialize(serialized_transaction)?;
-            deserialised_transactions.push(deserialised_transaction);
+        self.connect_block(block)?;
+        println!("New block mined {:?}", self.len());
+        Ok(())
+    }
+
+    fn validate_block(
+        previous_blocks: &[Block],
+        block: &Block,
+        utxo: &UtxoSet,
+        params: &ChainParams,
+        verify_only: bool,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let previous_hash = match previous_blocks.last() {
+            Some(previous_block) => previous_block.hash(),
+            None if hex::encode(block.hash()) == params.genesis_hash => return Ok(()),
+            None => {
+                return Err(RitCoinErrror::from(
+                    "Genesis block does not match the network genesis block",
+                ))
+            }
+        };
+        if block.get_previous_hash() != previous_hash.as_slice() {
+            return Err(RitCoinErrror::from(
+                "previous block hash in next block do not match current block hash",
+            ));
+        }
+        if block.get_bits() != Block::next_bits(previous_blocks, params) {
+            return Err(RitCoinErrror::from(
+                "Block difficulty bits do not match the expected target",
+            ));
         }
-        self.utxo.recalculate_utxos(&deserialised_transactions);
+        block.validate_header(Block::median_time_past(previous_blocks))?;
+        let block_height = previous_blocks.len() as u32;
+        let block_reward = sum_amounts([
+            Self::block_subsidy(block_height, params),
+            block.get_fees(utxo)?,
+        ])?;
+        block.validate_coinbase(block_height, block_reward)?;
+        block.validate_transactions(utxo, previous_blocks, params, verify_only)
+    }
+
+    fn connect_block(&mut self, block: Block) -> Result<(), RitCoinErrror<'static>> {
+        Self::validate_block(&self.blocks, &block, &self.utxo, &self.params, false)?;
+        let deserialised_transactions = block.get_deserialized_transactions()?;
+        let hash = block.hash();
+        self.store.append(&block)?;
+        let undo = self
+            .utxo
+            .recalculate_utxos(&deserialised_transactions, self.len() as u32);
+        self.store.write_undo(&hash, &undo)?;
+        self.utxo.set_tip(hash);
+        self.utxo.save(&self.params.data_path(UTXO_SET_FILE))?;
         self.blocks.push(block);
-        println!("New block mined {:?}", self.len());
         Ok(())
     }
 
+    // Pending transactions are checked again whenever the chain changes, entries the new
+    // tip made invalid would otherwise be picked for every block and fail it.
+    fn remove_invalid_pending(&self) -> Result<(), RitCoinErrror<'static>> {
+        pending_pool::remove_invalid_transactions(&self.utxo, &self.blocks, &self.params)
+    }
+
+    fn disconnect_tip(&mut self) -> Result<Block, RitCoinErrror<'static>> {
+        let block = match self.blocks.pop() {
+            Some(block) => block,
+            None => return Err(RitCoinErrror::from("There is no block to disconnect")),
+        };
+        let undo = self.store.read_undo(&block.hash())?;
+        self.utxo
+            .revert_utxos(&block.get_deserialized_transactions()?, undo);
+        self.store.truncate(self.blocks.len())?;
+        self.utxo.set_tip(self.tip_hash());
+        self.utxo.save(&self.params.data_path(UTXO_SET_FILE))?;
+        Ok(block)
+    }
+
+    fn reorganize(&mut self, blocks: Vec<Block>) -> Result<(), RitCoinErrror<'static>> {
+        let fork_height = self
+            .blocks
+            .iter()
+            .zip(&blocks)
+            .take_while(|(block, other)| block.hash() == other.hash())
+            .count();
+        let mut disconnected = vec![];
+        while self.len() > fork_height {
+            disconnected.push(self.disconnect_tip()?);
+        }
+        for block in blocks.into_iter().skip(fork_height) {
+            if let Err(e) = self.connect_block(block) {
+                while self.len() > fork_height {
+                    self.disconnect_tip()?;
+                }
+                for block in disconnected.into_iter().rev() {
+                    self.connect_block(block)?;
+                }
+                return Err(e);
+            }
+        }
+        self.remove_invalid_pending()?;
+        for block in disconnected.iter().rev() {
+            pending_pool::return_transactions(
+                block.get_transactions().get(1..).unwrap_or(&[]),
+                &self.utxo,
+                &self.blocks,
+                &self.params,
+            );
+        }
+        Ok(())
+    }
+
+    pub fn remote_chain_work(address: &str) -> Result<U256, RitCoinErrror<'static>> {
+        let client = Client::new();
+        let chain_work_url = address.to_owned() + CHAIN_WORK_RESOURCE;
+        let mut res = client.post(&chain_work_url).send()?;
+        if res.status() == StatusCode::OK {
+            let chain_work: String = res.json()?;
+            Ok(U256::from_be_bytes(&hex::decode(chain_work)?))
+        } else {
+            Err(RitCoinErrror::from(res.text()?))
+        }
+    }
+
     pub fn resolve_conflicts(&mut self) -> Result<(), RitCoinErrror<'static>> {
-        for node_address in &self.nodes {
+        for node_address in self.nodes.clone() {
             let address = "http://".to_owned() + &node_address.to_string();
-            if let Ok(node) = Self::exist(&address) {
-                if node.len() > self.len() {
-                    match node.verify_chain() {
-                        Ok(_) => {
-                            self.blocks = node.blocks;
-                            self.utxo = node.utxo;
+            let chain_work = self.chain_work()?;
+            // The work a node reports only decides whether its chain is worth downloading,
+            // chains are compared by the work of their validated headers
+            match Self::remote_chain_work(&address) {
+                Ok(remote_chain_work) if remote_chain_work > chain_work => (),
+                _ => continue,
+            }
+            if let Ok(node) = Self::exist(&address, &self.params) {
+                if node.blocks.first().map(Block::hash) != self.blocks.first().map(Block::hash) {
+                    println!("Node {} has a different genesis block", node_address);
+                    continue;
+                }
+                match Block::validate_headers(&node.blocks, &self.params) {
+                    Ok(node_chain_work) if node_chain_work > chain_work => {
+                        if let Err(e) = node
+                            .verify_chain()
+                            .and_then(|_| self.reorganize(node.blocks))
+                        {
+                            println!("{:?}", e)
                         }
-                        Err(e) => println!("{:?}", e),
                     }
+                    Ok(_) => (),
+                    Err(e) => println!("{:?}", e),
                 }
             }
         }
@@ -115,29 +319,21 @@ impl BlockChain {
     }
 
     pub fn verify_chain(&self) -> Result<(), RitCoinErrror<'static>> {
-        self.blocks[0].validate_transactions(&self.utxo, true)?;
-        for i in 0..self.blocks.len() - 1 {
-            self.blocks[i + 1].validate_transactions(&self.utxo, true)?;
-            if !(self.blocks[i].hash() == self.blocks[i + 1].get_previous_hash()) {
-                return Err(RitCoinErrror::from(
-                    "previous block hash in next block do not match current block hash",
-                ));
-            }
+        let mut utxo = UtxoSet::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            Self::validate_block(&self.blocks[..height], block, &utxo, &self.params, true)?;
+            utxo.recalculate_utxos(&block.get_deserialized_transactions()?, height as u32);
         }
         Ok(())
     }
 
-    pub fn genesis_block(block_height: u32) -> Result<Block, RitCoinErrror<'static>> {
-        let private_key = wallet::wif_to_private_key_from_file(MINER_KEY_PATH)?;
-        let public_key = wallet::private_key_to_public_key(&private_key)?;
-        let pk_hash = wallet::pk_hash_from_public_key(&public_key);
-        let coinbase_transaction =
-            CoinBaseTransaction::new(&pk_hash, block_height, DEFAULT_COINBASE_AMOUNT);
-        let coinbase_transaction_serialized = serializer::serialize(&coinbase_transaction)?;
-        Ok(Block::new(
-            vec![0; 32],
-            vec![coinbase_transaction_serialized],
-        ))
+    pub fn block_subsidy(block_height: u32, params: &ChainParams) -> u64 {
+        let halvings = block_height / params.subsidy_halving_interval;
+        if halvings >= 64 {
+            0
+        } else {
+            params.initial_block_subsidy >> halvings
+        }
     }
 
     pub fn add_node(&mut self, node: &str) {
@@ -153,13 +349,264 @@ impl BlockChain {
         &self.nodes
     }
 
+    pub fn get_blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
     pub fn len(&self) -> usize {
         self.blocks.len()
     }
 
+    // Blocks were validated when they were connected, their work can be summed as is.
+    pub fn chain_work(&self) -> Result<U256, RitCoinErrror<'static>> {
+        self.blocks
+            .iter()
+            .try_fold(U256::zero(), |acc, block| Ok(acc + block.work()?))
+    }
+
     pub fn get_balance(&self, address: &str) -> Result<u64, RitCoinErrror<'static>> {
-        let pk_hash = wallet::address_to_pkhash(address)?;
+        let pk_hash = wallet::address_to_pkhash(address, &self.params)?;
         let utxos = self.utxo.by_pkhash(&pk_hash);
         Ok(UtxoSet::get_total_amount(&utxos))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{error_message, mine_block, test_params};
+
+    const PRIVATE_KEY: [u8; 32] = [1; 32];
+    // Coinbases of the competing branch pay elsewhere so both branches differ.
+    const OTHER_PKHASH: [u8; 20] = [7; 20];
+
+    fn pkhash() -> Vec<u8> {
+        wallet::pk_hash_from_public_key(&wallet::private_key_to_public_key(&PRIVATE_KEY).unwrap())
+    }
+
+    fn new_chain(params: &ChainParams) -> BlockChain {
+        let mut blockchain = BlockChain {
+            blocks: vec![],
+            nodes: vec![],
+            utxo: UtxoSet::new(),
+            store: BlockStore::open(&params.data_path(BLOCKS_DIR)).unwrap(),
+            params: params.clone(),
+        };
+        blockchain
+            .connect_block(Block::genesis(params).unwrap())
+            .unwrap();
+        blockchain
+    }
+
+    // Mines the block after blocks, its coinbase pays the subsidy to pkhash.
+    fn next_block(
+        blocks: &[Block],
+        pkhash: &[u8],
+        transactions: &[Transaction],
+        params: &ChainParams,
+    ) -> Block {
+        let height = blocks.len() as u32;
+        let mut block_transactions = vec![CoinBaseTransaction::new(
+            pkhash,
+            height,
+            BlockChain::block_subsidy(height, params),
+        )];
+        block_transactions.extend_from_slice(transactions);
+        mine_block(blocks, &block_transactions, params)
+    }
+
+    fn spend(utxo: &Utxo) -> Transaction {
+        let mut transaction = Transaction::new(
+            vec![Input::create(utxo)],
+            vec![Output::create(utxo.get_output().get_amount(), &[9; 20])],
+        );
+        transaction.sign(&PRIVATE_KEY, SIGHASH_ALL).unwrap();
+        transaction
+    }
+
+    fn utxos_of(blocks: &[Block]) -> UtxoSet {
+        let mut utxo = UtxoSet::new();
+        for (height, block) in blocks.iter().enumerate() {
+            utxo.recalculate_utxos(
+                &block.get_deserialized_transactions().unwrap(),
+                height as u32,
+            );
+        }
+        utxo.set_tip(blocks.last().unwrap().hash());
+        utxo
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<Vec<u8>> {
+        blocks.iter().map(Block::hash).collect()
+    }
+
+    // Connects a block paying the test key and a block spending that coinbase with
+    // the returned transaction.
+    fn chain_with_spend(params: &ChainParams) -> (BlockChain, Transaction) {
+        let mut blockchain = new_chain(params);
+        let block = next_block(&blockchain.blocks, &pkhash(), &[], params);
+        blockchain.connect_block(block).unwrap();
+        let spend = spend(blockchain.utxo.by_pkhash(&pkhash())[0]);
+        let block = next_block(
+            &blockchain.blocks,
+            &pkhash(),
+            std::slice::from_ref(&spend),
+            params,
+        );
+        blockchain.connect_block(block).unwrap();
+        (blockchain, spend)
+    }
+
+    // Branch forking after the first block of blockchain, one block longer.
+    fn competing_branch(blockchain: &BlockChain, params: &ChainParams) -> Vec<Block> {
+        let mut blocks = blockchain.blocks[..2].to_vec();
+        for _ in 0..2 {
+            let block = next_block(&blocks, &OTHER_PKHASH, &[], params);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn disconnect_then_connect_restores_the_utxo_set() {
+        let params = test_params("disconnect_connect");
+        let (mut blockchain, spend) = chain_with_spend(&params);
+        let utxo = blockchain.utxo.clone();
+        let spent_outpoint = spend.get_tx_in()[0].get_previous_output().clone();
+        assert!(blockchain.utxo.get(&spent_outpoint).is_none());
+
+        let block = blockchain.disconnect_tip().unwrap();
+        assert_eq!(blockchain.utxo, utxos_of(&blockchain.blocks));
+        assert!(blockchain.utxo.get(&spent_outpoint).is_some());
+        assert_eq!(
+            blockchain.utxo.get_tip(),
+            blockchain.blocks[1].hash().as_slice()
+        );
+        assert_eq!(blockchain.store.load_blocks().unwrap().len(), 2);
+
+        blockchain.connect_block(block).unwrap();
+        assert_eq!(blockchain.utxo, utxo);
+        assert_eq!(
+            hashes(&blockchain.store.load_blocks().unwrap()),
+            hashes(&blockchain.blocks)
+        );
+    }
+
+    #[test]
+    fn utxo_set_is_rebuilt_when_its_tip_does_not_match() {
+        let params = test_params("rebuild_utxos");
+        let (blockchain, _) = chain_with_spend(&params);
+        let path = params.data_path(UTXO_SET_FILE);
+        assert_eq!(UtxoSet::load(&path).unwrap(), blockchain.utxo);
+
+        // A saved set tagged with the current tip is trusted as it is
+        let mut stale = utxos_of(&blockchain.blocks[..2]);
+        stale.set_tip(blockchain.tip_hash());
+        stale.save(&path).unwrap();
+        assert_eq!(BlockChain::new(params.clone()).unwrap().utxo, stale);
+
+        // A set saved at another tip is rebuilt from the blocks and saved again
+        stale.set_tip(blockchain.blocks[1].hash());
+        stale.save(&path).unwrap();
+        let reopened = BlockChain::new(params.clone()).unwrap();
+        assert_eq!(reopened.utxo, blockchain.utxo);
+        assert_eq!(UtxoSet::load(&path).unwrap(), blockchain.utxo);
+
+        // So is a missing one
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(BlockChain::new(params).unwrap().utxo, blockchain.utxo);
+    }
+
+    #[test]
+    fn reorganize_onto_a_branch_with_more_work() {
+        let params = test_params("reorganize");
+        let (mut blockchain, spend) = chain_with_spend(&params);
+        let branch = competing_branch(&blockchain, &params);
+
+        blockchain.reorganize(branch.clone()).unwrap();
+        assert_eq!(hashes(&blockchain.blocks), hashes(&branch));
+        assert_eq!(blockchain.utxo, utxos_of(&branch));
+        assert_eq!(
+            hashes(
+                &BlockStore::open(&params.data_path(BLOCKS_DIR))
+                    .unwrap()
+                    .load_blocks()
+                    .unwrap()
+            ),
+            hashes(&branch)
+        );
+        // The spend of the disconnected block is still valid on the new branch
+        assert_eq!(
+            pending_pool::get_last_transactions(None, &params).unwrap(),
+            vec![serializer::serialize(&spend)]
+        );
+    }
+
+    #[test]
+    fn failed_reorganization_restores_the_active_chain() {
+        let params = test_params("failed_reorganize");
+        let (mut blockchain, _) = chain_with_spend(&params);
+        let blocks = blockchain.blocks.clone();
+        let utxo = blockchain.utxo.clone();
+        let mut branch = competing_branch(&blockchain, &params);
+        // The last block of the branch pays itself more than the subsidy
+        branch.pop();
+        let coinbase: Transaction = CoinBaseTransaction::new(&OTHER_PKHASH, 3, 1000);
+        let block = mine_block(&branch, &[coinbase], &params);
+        branch.push(block);
+
+        assert!(blockchain.reorganize(branch).is_err());
+        assert_eq!(hashes(&blockchain.blocks), hashes(&blocks));
+        assert_eq!(blockchain.utxo, utxo);
+        assert_eq!(
+            hashes(&blockchain.store.load_blocks().unwrap()),
+            hashes(&blocks)
+        );
+        assert!(pending_pool::get_last_transactions(None, &params)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn block_subsidy_halves_on_schedule() {
+        let params = ChainParams::main();
+        let interval = params.subsidy_halving_interval;
+        assert_eq!(BlockChain::block_subsidy(0, &params), 50);
+        assert_eq!(BlockChain::block_subsidy(interval - 1, &params), 50);
+        assert_eq!(BlockChain::block_subsidy(interval, &params), 25);
+        assert_eq!(BlockChain::block_subsidy(2 * interval, &params), 12);
+        // Shifting by 64 would overflow, the subsidy ends after 64 halvings
+        let params = ChainParams {
+            initial_block_subsidy: u64::MAX,
+            ..params
+        };
+        assert_eq!(BlockChain::block_subsidy(63 * interval, &params), 1);
+        assert_eq!(BlockChain::block_subsidy(64 * interval, &params), 0);
+        assert_eq!(BlockChain::block_subsidy(u32::MAX, &params), 0);
+    }
+
+    #[test]
+    fn coinbase_may_claim_the_subsidy_and_fees() {
+        let params = test_params("coinbase_fees");
+        let mut blockchain = new_chain(&params);
+        let block = next_block(&blockchain.blocks, &pkhash(), &[], &params);
+        blockchain.connect_block(block).unwrap();
+        let utxo = blockchain.utxo.by_pkhash(&pkhash())[0].clone();
+        let mut spend = Transaction::new(
+            vec![Input::create(&utxo)],
+            vec![Output::create(utxo.get_output().get_amount() - 3, &[9; 20])],
+        );
+        spend.sign(&PRIVATE_KEY, SIGHASH_ALL).unwrap();
+        let reward = BlockChain::block_subsidy(2, &params) + 3;
+        let validate = |amount| {
+            let coinbase = CoinBaseTransaction::new(&pkhash(), 2, amount);
+            let block = mine_block(&blockchain.blocks, &[coinbase, spend.clone()], &params);
+            BlockChain::validate_block(&blockchain.blocks, &block, &blockchain.utxo, &params, false)
+        };
+        assert!(validate(reward).is_ok());
+        assert_eq!(
+            error_message(validate(reward + 1)),
+            "Coinbase amount exceeds block reward"
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::errors::*;
+use std::env;
+use std::fs;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Test,
+    Regtest,
+}
+
+impl Network {
+    // Network is chosen with a --testnet or --regtest startup argument, mainnet otherwise.
+    pub fn from_args() -> Self {
+        let mut network = Network::Main;
+        for arg in env::args().skip(1) {
+            match arg.as_str() {
+                "--testnet" => network = Network::Test,
+                "--regtest" => network = Network::Regtest,
+                _ => (),
+            }
+        }
+        network
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub pubkey_address_prefix: u8,
+    pub script_address_prefix: u8,
+    pub wif_prefix: u8,
+    pub pow_limit_bits: u32,
+    pub target_block_interval: u64,
+    pub retarget_interval: usize,
+    pub pow_no_retargeting: bool,
+    pub initial_block_subsidy: u64,
+    pub subsidy_halving_interval: u32,
+    pub max_money: u64,
+    pub coinbase_maturity: u32,
+    pub max_data_carrier_size: usize,
+    pub default_port: u16,
+    pub data_dir: &'static str,
+    pub genesis_timestamp: u64,
+    pub genesis_nonce: u64,
+    pub genesis_hash: &'static str,
+}
+
+impl ChainParams {
+    pub fn main() -> Self {
+        Self {
+            pubkey_address_prefix: 0x00,
+            script_address_prefix: 0x05,
+            wif_prefix: 0x80,
+            pow_limit_bits: 0x1f00_ffff,
+            target_block_interval: 30,
+            retarget_interval: 10,
+            pow_no_retargeting: false,
+            initial_block_subsidy: 50,
+            subsidy_halving_interval: 210,
+            // Above the total subsidy ever paid, like Bitcoin's 21 million limit
+            max_money: 21_000,
+            coinbase_maturity: 100,
+            max_data_carrier_size: 80,
+            default_port: 3000,
+            data_dir: "data",
+            genesis_timestamp: 1_577_836_800,
+            genesis_nonce: 45558,
+            genesis_hash: "00004b93bdc4a565034b92ecea272ca55d8ed99ffb954747b342c67c890a9b21",
+        }
+    }
+
+    pub fn test() -> Self {
+        Self {
+            pubkey_address_prefix: 0x6f,
+            script_address_prefix: 0xc4,
+            wif_prefix: 0xef,
+            default_port: 13000,
+            data_dir: "data/testnet",
+            genesis_timestamp: 1_577_836_801,
+            genesis_nonce: 1157,
+            genesis_hash: "0000790e570900cf8ec2ddee424f1d10acfc77c1b2909e941bf563b5a667d18d",
+            ..Self::main()
+        }
+    }
+
+    pub fn regtest() -> Self {
+        Self {
+            pow_limit_bits: 0x207f_ffff,
+            pow_no_retargeting: true,
+            subsidy_halving_interval: 150,
+            default_port: 23000,
+            data_dir: "data/regtest",
+            genesis_timestamp: 1_577_836_802,
+            genesis_nonce: 3,
+            genesis_hash: "1a6115a22de5bf3da010b5d2dc735e261ea4347ebfe63a65cdb892716ed6bb1b",
+            ..Self::test()
+        }
+    }
+
+    pub fn from_network(network: Network) -> Self {
+        match network {
+            Network::Main => Self::main(),
+            Network::Test => Self::test(),
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+    // The subsidy schedule and retargeting divide by these intervals, so they must not
+    // be zero, and a retarget window needs at least two blocks to measure a timespan.
+    pub fn validate(&self) -> Result<(), RitCoinErrror<'static>> {
+        if self.subsidy_halving_interval == 0 {
+            return Err(RitCoinErrror::from(
+                "Subsidy halving interval must not be zero",
+            ));
+        }
+        if self.retarget_interval < 2 || self.target_block_interval == 0 {
+            return Err(RitCoinErrror::from(
+                "Retarget interval must span at least two blocks of a non-zero block interval",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn create_data_dir(&self) -> Result<(), RitCoinErrror<'static>> {
+        fs::create_dir_all(self.data_dir)?;
+        Ok(())
+    }
+
+    pub fn data_path(&self, file_name: &str) -> String {
+        format!("{}/{}", self.data_dir, file_name)
+    }
+
+    pub fn default_node_address(&self) -> String {
+        format!("http://0.0.0.0:{}", self.default_port)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::main()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::error_message;
+
+    #[test]
+    fn intervals_used_as_divisors_are_validated() {
+        for network in [Network::Main, Network::Test, Network::Regtest].iter() {
+            assert!(ChainParams::from_network(*network).validate().is_ok());
+        }
+        let params = ChainParams {
+            subsidy_halving_interval: 0,
+            ..ChainParams::main()
+        };
+        assert_eq!(
+            error_message(params.validate()),
+            "Subsidy halving interval must not be zero"
+        );
+        for (retarget_interval, target_block_interval) in [(1, 30), (10, 0)].iter() {
+            let params = ChainParams {
+                retarget_interval: *retarget_interval,
+                target_block_interval: *target_block_interval,
+                ..ChainParams::main()
+            };
+            assert_eq!(
+                error_message(params.validate()),
+                "Retarget interval must span at least two blocks of a non-zero block interval"
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::chain_params::ChainParams;
 use crate::errors::*;
 use crate::miner_cli;
 use crate::wallet_cli;
@@ -6,33 +7,247 @@ pub use std::fs::{self, File};
 pub use std::io::prelude::*;
 use std::io::stdin;
 
-pub const ADDRESS_PATH: &str = "data/address.txt";
-pub const PRIVATE_KEY_PATH: &str = "data/private_key.txt";
+pub const ADDRESS_FILE: &str = "address.txt";
+pub const PRIVATE_KEY_FILE: &str = "private_key.txt";
 
 fn read_cli(
     command: &str,
     prepared_transactions: &mut Vec<Vec<u8>>,
     ritcoin_state: Arc<RitCoinState>,
 ) -> Result<(), RitCoinErrror<'static>> {
+    let params = &ritcoin_state.params;
     match command {
-        "new -m" => miner_cli::new(),
-        "new" => wallet_cli::new(ADDRESS_PATH),
+        "new -m" => miner_cli::new(params),
+        "new" => wallet_cli::new(&params.data_path(ADDRESS_FILE), params),
+        command if command.starts_with("import -r") => {
+            let raw_tx = command
+                .split_ascii_whitespace()
+                .nth(2)
+                .ok_or(RitCoinErrror::from("usage: import -r <raw tx>"))?;
+            wallet_cli::import_raw(raw_tx, prepared_transactions, params)
+        }
         command if command.starts_with("import -m") => {
             let path = command.split_ascii_whitespace().collect::<Vec<&str>>()[2];
-            miner_cli::import(path)
+            miner_cli::import(path, params)
         }
         command if command.starts_with("import") => {
             let path = command.split_ascii_whitespace().collect::<Vec<&str>>()[1];
-            wallet_cli::import(path, ADDRESS_PATH)
+            wallet_cli::import(path, &params.data_path(ADDRESS_FILE), params)
+        }
+        "pubkey" => wallet_cli::public_key(params),
+        command if command.starts_with("multisig send") => {
+            let command = command.trim_start_matches("multisig send");
+            const USAGE: &str = "usage: multisig send <required>, <public keys>, <amount>[, <fee>]";
+            let send_parameters = command.split(',').map(str::trim).collect::<Vec<&str>>();
+            let required = send_parameters
+                .first()
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .parse::<usize>()?;
+            let mut public_keys = vec![];
+            for public_key in send_parameters
+                .get(1)
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .split_ascii_whitespace()
+            {
+                public_keys.push(hex::decode(public_key)?);
+            }
+            let amount = send_parameters
+                .get(2)
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .parse::<u64>()?;
+            let fee = match send_parameters.get(3) {
+                Some(fee) => fee.parse::<u64>()?,
+                None => 0,
+            };
+            wallet_cli::send_multisig(
+                required,
+                &public_keys,
+                amount,
+                fee,
+                prepared_transactions,
+                ritcoin_state,
+            )
+        }
+        command if command.starts_with("multisig address") => {
+            let command = command.trim_start_matches("multisig address");
+            const USAGE: &str = "usage: multisig address <required>, <public keys>";
+            let address_parameters = command.split(',').map(str::trim).collect::<Vec<&str>>();
+            let required = address_parameters
+                .first()
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .parse::<usize>()?;
+            let mut public_keys = vec![];
+            for public_key in address_parameters
+                .get(1)
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .split_ascii_whitespace()
+            {
+                public_keys.push(hex::decode(public_key)?);
+            }
+            wallet_cli::multisig_address(required, &public_keys, params)
+        }
+        command if command.starts_with("multisig spend") => {
+            let command = command.replace(',', "");
+            const USAGE: &str =
+                "usage: multisig spend <outpoint>, <address>, <amount>[, <fee>[, <redeem script>]]";
+            let spend_parameters = command.split_ascii_whitespace().collect::<Vec<&str>>();
+            let outpoint = spend_parameters.get(2).ok_or(RitCoinErrror::from(USAGE))?;
+            let receiver_address = spend_parameters.get(3).ok_or(RitCoinErrror::from(USAGE))?;
+            let amount = spend_parameters
+                .get(4)
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .parse::<u64>()?;
+            let fee = match spend_parameters.get(5) {
+                Some(fee) => fee.parse::<u64>()?,
+                None => 0,
+            };
+            let redeem_script = match spend_parameters.get(6) {
+                Some(redeem_script) => Some(hex::decode(redeem_script)?),
+                None => None,
+            };
+            wallet_cli::spend_multisig(
+                outpoint,
+                receiver_address,
+                amount,
+                fee,
+                redeem_script,
+                ritcoin_state,
+            )
+        }
+        command if command.starts_with("anchor") => {
+            let command = command.replace(',', "");
+            const USAGE: &str = "usage: anchor <hex data>[, <fee>] or anchor -f <path>[, <fee>]";
+            let anchor_parameters = command.split_ascii_whitespace().collect::<Vec<&str>>();
+            match *anchor_parameters.get(1).ok_or(RitCoinErrror::from(USAGE))? {
+                "-f" => {
+                    let path = anchor_parameters.get(2).ok_or(RitCoinErrror::from(USAGE))?;
+                    let fee = match anchor_parameters.get(3) {
+                        Some(fee) => fee.parse::<u64>()?,
+                        None => 0,
+                    };
+                    wallet_cli::anchor_file(path, fee, prepared_transactions, ritcoin_state)
+                }
+                data => {
+                    let fee = match anchor_parameters.get(2) {
+                        Some(fee) => fee.parse::<u64>()?,
+                        None => 0,
+                    };
+                    wallet_cli::anchor(
+                        &hex::decode(data)?,
+                        fee,
+                        prepared_transactions,
+                        ritcoin_state,
+                    )
+                }
+            }
+        }
+        "htlc secret" => {
+            wallet_cli::htlc_secret();
+            Ok(())
+        }
+        command if command.starts_with("htlc create") => {
+            let command = command.replace(',', "");
+            const USAGE: &str =
+                "usage: htlc create <address>, <secret hash>, <lock time>, <amount>[, <fee>]";
+            let create_parameters = command.split_ascii_whitespace().collect::<Vec<&str>>();
+            let recipient_address = create_parameters.get(2).ok_or(RitCoinErrror::from(USAGE))?;
+            let secret_hash =
+                hex::decode(create_parameters.get(3).ok_or(RitCoinErrror::from(USAGE))?)?;
+            let lock_time = create_parameters
+                .get(4)
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .parse::<u32>()?;
+            let amount = create_parameters
+                .get(5)
+                .ok_or(RitCoinErrror::from(USAGE))?
+                .parse::<u64>()?;
+            let fee = match create_parameters.get(6) {
+                Some(fee) => fee.parse::<u64>()?,
+                None => 0,
+            };
+            wallet_cli::htlc_create(
+                recipient_address,
+                &secret_hash,
+                lock_time,
+                amount,
+                fee,
+                prepared_transactions,
+                ritcoin_state,
+            )
+        }
+        command if command.starts_with("htlc claim") => {
+            let command = command.replace(',', "");
+            const USAGE: &str = "usage: htlc claim <outpoint>, <redeem script>, <secret>[, <fee>]";
+            let claim_parameters = command.split_ascii_whitespace().collect::<Vec<&str>>();
+            let outpoint = claim_parameters.get(2).ok_or(RitCoinErrror::from(USAGE))?;
+            let redeem_script =
+                hex::decode(claim_parameters.get(3).ok_or(RitCoinErrror::from(USAGE))?)?;
+            let secret = hex::decode(claim_parameters.get(4).ok_or(RitCoinErrror::from(USAGE))?)?;
+            let fee = match claim_parameters.get(5) {
+                Some(fee) => fee.parse::<u64>()?,
+                None => 0,
+            };
+            wallet_cli::spend_htlc(
+                outpoint,
+                &redeem_script,
+                Some(secret),
+                fee,
+                prepared_transactions,
+                ritcoin_state,
+            )
+        }
+        command if command.starts_with("htlc refund") => {
+            let command = command.replace(',', "");
+            const USAGE: &str = "usage: htlc refund <outpoint>, <redeem script>[, <fee>]";
+            let refund_parameters = command.split_ascii_whitespace().collect::<Vec<&str>>();
+            let outpoint = refund_parameters.get(2).ok_or(RitCoinErrror::from(USAGE))?;
+            let redeem_script =
+                hex::decode(refund_parameters.get(3).ok_or(RitCoinErrror::from(USAGE))?)?;
+            let fee = match refund_parameters.get(4) {
+                Some(fee) => fee.parse::<u64>()?,
+                None => 0,
+            };
+            wallet_cli::spend_htlc(
+                outpoint,
+                &redeem_script,
+                None,
+                fee,
+                prepared_transactions,
+                ritcoin_state,
+            )
+        }
+        command if command.starts_with("htlc extract") => {
+            let outpoint = command
+                .split_ascii_whitespace()
+                .nth(2)
+                .ok_or(RitCoinErrror::from("usage: htlc extract <outpoint>"))?;
+            wallet_cli::htlc_extract(outpoint, ritcoin_state)
+        }
+        command if command.starts_with("cosign") => {
+            let raw_tx = command
+                .split_ascii_whitespace()
+                .nth(1)
+                .ok_or(RitCoinErrror::from("usage: cosign <raw tx>"))?;
+            wallet_cli::cosign(raw_tx, prepared_transactions, ritcoin_state)
         }
         command if command.starts_with("send") => {
             let command = command.replace(',', "");
             let send_parameters = command.split_ascii_whitespace().collect::<Vec<&str>>();
             let recipient_address = send_parameters[1];
             let amount = send_parameters[2].parse::<u64>()?;
+            let fee = match send_parameters.get(3) {
+                Some(fee) => fee.parse::<u64>()?,
+                None => 0,
+            };
+            let lock_time = match send_parameters.get(4) {
+                Some(lock_time) => lock_time.parse::<u32>()?,
+                None => 0,
+            };
             wallet_cli::send(
                 recipient_address,
                 amount,
+                fee,
+                lock_time,
                 prepared_transactions,
                 ritcoin_state,
             )
@@ -41,15 +256,47 @@ fn read_cli(
             let broadcast_parameters = command.splitn(2, ' ').collect::<Vec<&str>>();
             match broadcast_parameters.get(1) {
                 Some(broadcast_parameters) if broadcast_parameters.starts_with("-t") => {
-                    let serialized_tx = command.splitn(2, ' ').collect::<Vec<&str>>()[1];
-                    wallet_cli::broadcast(serialized_tx, prepared_transactions, true)
+                    let serialized_tx = broadcast_parameters.trim_start_matches("-t").trim();
+                    wallet_cli::broadcast(
+                        serialized_tx,
+                        prepared_transactions,
+                        &ChainParams::test().default_node_address(),
+                    )
                 }
                 _ => {
                     let serialized_tx = broadcast_parameters[1];
-                    wallet_cli::broadcast(serialized_tx, prepared_transactions, false)
+                    wallet_cli::broadcast(
+                        serialized_tx,
+                        prepared_transactions,
+                        &params.default_node_address(),
+                    )
                 }
             }
         }
+        command if command.starts_with("decode") => {
+            let raw_tx = command
+                .split_ascii_whitespace()
+                .nth(1)
+                .ok_or(RitCoinErrror::from("usage: decode <raw tx>"))?;
+            wallet_cli::decode_raw(raw_tx, params)
+        }
+        command if command.starts_with("script disasm") => {
+            let script_hex = command
+                .split_ascii_whitespace()
+                .nth(2)
+                .ok_or(RitCoinErrror::from("usage: script disasm <script hex>"))?;
+            wallet_cli::disassemble(script_hex)
+        }
+        command if command.starts_with("script asm") => {
+            let asm = command.trim_start_matches("script asm");
+            wallet_cli::assemble(asm)
+        }
+        command if command.starts_with("export") => {
+            let (_, serialized_tx) = command
+                .split_once(' ')
+                .ok_or(RitCoinErrror::from("usage: export <serialized tx>"))?;
+            wallet_cli::export_raw(serialized_tx)
+        }
         "unlock all" => {
             wallet_cli::unlock_all(prepared_transactions);
             Ok(())
@@ -9,6 +9,7 @@ pub enum RitCoinErrror<'a> {
     StrError(&'a str),
     StringError(String),
     ReqwestError(reqwest::Error),
+    MissingInputs(Vec<crate::transaction::OutPoint>),
 }
 
 impl<'a> From<bs58::decode::Error> for RitCoinErrror<'a> {
@@ -1,3 +1,4 @@
+use crate::errors::*;
 use crate::pending_pool;
 use crate::*;
 use actix_web::{web, HttpResponse};
@@ -13,25 +14,40 @@ pub fn handle_submit_tx(
     ritcoin_state: web::Data<Arc<RitCoinState>>,
 ) -> HttpResponse {
     if let Ok(blockchain_state) = ritcoin_state.blockchain.lock() {
-        if pending_pool::accept_serialized_transaction(
+        match pending_pool::accept_serialized_transaction(
             &serialized_tx_form.tx,
             blockchain_state.get_utxos_ref(),
-        )
-        .is_ok()
-        {
-            HttpResponse::Ok().body(format!("tx successfully saved to mempool"))
-        } else {
-            HttpResponse::BadRequest().body(format!("invalid tx"))
+            blockchain_state.get_blocks(),
+            &ritcoin_state.params,
+        ) {
+            Ok(()) => HttpResponse::Ok().body(format!("tx successfully saved to mempool")),
+            Err(RitCoinErrror::MissingInputs(outpoints)) => {
+                let outpoints: Vec<_> = outpoints.iter().map(ToString::to_string).collect();
+                HttpResponse::BadRequest().body(format!(
+                    "invalid tx, unknown inputs {}",
+                    outpoints.join(", ")
+                ))
+            }
+            Err(_) => HttpResponse::BadRequest().body(format!("invalid tx")),
         }
     } else {
         HttpResponse::BadRequest().body(format!("handle_submit_tx error occured"))
     }
 }
 
-pub fn handle_pendings() -> Result<HttpResponse, HttpResponse> {
-    let pending_transactions = pending_pool::get_last_transactions_deserialized(None);
+pub fn handle_pendings(
+    ritcoin_state: web::Data<Arc<RitCoinState>>,
+) -> Result<HttpResponse, HttpResponse> {
+    let pending_transactions =
+        pending_pool::get_last_transactions_deserialized(None, &ritcoin_state.params);
     pending_transactions
-        .map(|transactions| HttpResponse::Ok().json(transactions))
+        .map(|transactions| {
+            let views: Vec<_> = transactions
+                .iter()
+                .map(|transaction| transaction.to_json(&ritcoin_state.params))
+                .collect();
+            HttpResponse::Ok().json(views)
+        })
         .map_err(|e| HttpResponse::InternalServerError().body(format!("{:?}", e)))
 }
 
@@ -58,3 +74,14 @@ pub fn handle_chain_length(ritcoin_state: web::Data<Arc<RitCoinState>>) -> HttpR
         HttpResponse::BadRequest().body(format!("handle_chain_length error occured"))
     }
 }
+
+pub fn handle_chain_work(ritcoin_state: web::Data<Arc<RitCoinState>>) -> HttpResponse {
+    if let Ok(blockchain_state) = ritcoin_state.blockchain.lock() {
+        match blockchain_state.chain_work() {
+            Ok(chain_work) => HttpResponse::Ok().json(chain_work.to_hex()),
+            Err(e) => HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        }
+    } else {
+        HttpResponse::BadRequest().body("handle_chain_work error occured")
+    }
+}
@@ -7,8 +7,22 @@ pub fn sha256(value: &[u8]) -> Vec<u8> {
     hasher.result().to_vec()
 }
 
+pub fn sha1(value: &[u8]) -> Vec<u8> {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(value);
+    hasher.digest().bytes().to_vec()
+}
+
 pub fn ripemd160(value: &[u8]) -> Vec<u8> {
     let mut hasher = Ripemd160::new();
     hasher.input(value);
     hasher.result().to_vec()
 }
+
+pub fn hash160(value: &[u8]) -> Vec<u8> {
+    ripemd160(&sha256(value))
+}
+
+pub fn hash256(value: &[u8]) -> Vec<u8> {
+    sha256(&sha256(value))
+}
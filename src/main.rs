@@ -1,5 +1,7 @@
 mod block;
+mod block_store;
 mod blockchain;
+mod chain_params;
 mod cli;
 mod errors;
 mod handlers;
@@ -8,32 +10,43 @@ mod merkle;
 mod miner_cli;
 mod opcodes;
 mod pending_pool;
+mod pow;
 mod script;
+mod script_asm;
 mod serializer;
 mod server;
+#[cfg(test)]
+mod test_utils;
 mod transaction;
 mod utxo_set;
 mod wallet;
 mod wallet_cli;
 use blockchain::BlockChain;
+use chain_params::{ChainParams, Network};
 use cli::*;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 pub struct RitCoinState {
+    params: ChainParams,
     blockchain: Mutex<BlockChain>,
 }
 
 impl RitCoinState {
-    fn new() -> Self {
-        Self {
-            blockchain: Mutex::new(BlockChain::new()),
-        }
+    fn new(params: ChainParams) -> Result<Self, errors::RitCoinErrror<'static>> {
+        params.validate()?;
+        params.create_data_dir()?;
+        Ok(Self {
+            blockchain: Mutex::new(BlockChain::new(params.clone())?),
+            params,
+        })
     }
 }
 
 fn main() -> std::io::Result<()> {
-    let ritcoin_state = Arc::new(RitCoinState::new());
+    let params = ChainParams::from_network(Network::from_args());
+    let ritcoin_state =
+        Arc::new(RitCoinState::new(params).map_err(|e| std::io::Error::other(format!("{:?}", e)))?);
     let ritcoin_state_cloned = ritcoin_state.clone();
     thread::spawn(move || {
         while let Err(e) = cli(ritcoin_state_cloned.clone()) {
@@ -1,11 +1,7 @@
 use crate::hash::*;
-use sha2::{Digest, Sha256};
 
 fn merge(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    hasher.input(lhs);
-    hasher.input(rhs);
-    hasher.result().to_vec()
+    hash256(&[lhs, rhs].concat())
 }
 
 fn merkle_root(mut transaction_hashes: Vec<Vec<u8>>) -> Vec<u8> {
@@ -30,6 +26,8 @@ pub fn get_merkle_root(serialized_transactions: &[Vec<u8>]) -> Vec<u8> {
     let mut transaction_hashes = vec![];
     serialized_transactions
         .iter()
-        .for_each(|serialized_transaction| transaction_hashes.push(sha256(serialized_transaction)));
+        .for_each(|serialized_transaction| {
+            transaction_hashes.push(hash256(serialized_transaction))
+        });
     merkle_root(transaction_hashes)
 }
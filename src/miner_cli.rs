@@ -1,16 +1,20 @@
+use crate::chain_params::ChainParams;
 use crate::errors::*;
 use crate::wallet_cli;
 use crate::*;
 
-const MINER_ADDRESS_PATH: &str = "data/miner_address.txt";
-const MINER_PRIVATE_KEY_PATH: &str = "data/miner_key.txt";
+const MINER_ADDRESS_FILE: &str = "miner_address.txt";
 
-pub fn new() -> Result<(), RitCoinErrror<'static>> {
-    wallet_cli::new(MINER_ADDRESS_PATH)
+pub fn new(params: &ChainParams) -> Result<(), RitCoinErrror<'static>> {
+    wallet_cli::new(&params.data_path(MINER_ADDRESS_FILE), params)
 }
 
-pub fn import(private_key_path: &str) -> Result<(), RitCoinErrror<'static>> {
-    wallet_cli::import(private_key_path, MINER_ADDRESS_PATH)
+pub fn import(private_key_path: &str, params: &ChainParams) -> Result<(), RitCoinErrror<'static>> {
+    wallet_cli::import(
+        private_key_path,
+        &params.data_path(MINER_ADDRESS_FILE),
+        params,
+    )
 }
 
 pub fn add_node(
@@ -1,60 +1,130 @@
+use crate::block::Block;
+use crate::chain_params::ChainParams;
 use crate::errors::*;
 use crate::serializer;
 use crate::transaction::Transaction;
 use crate::utxo_set::UtxoSet;
-use crate::wallet;
-pub use std::fs::{self, File, OpenOptions};
+pub use std::fs::{File, OpenOptions};
 pub use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::io::{BufRead, BufReader};
 
-const PENDING_POOL_PATH: &str = "data/pending_pool.txt";
+const PENDING_POOL_FILE: &str = "pending_pool.txt";
 
-fn save_to_mempool(serialized_transaction: &[u8]) -> Result<(), RitCoinErrror<'static>> {
+fn save_to_mempool(
+    serialized_transaction: &[u8],
+    params: &ChainParams,
+) -> Result<(), RitCoinErrror<'static>> {
     let mut file = OpenOptions::new()
         .append(true)
         .create(true)
-        .open(PENDING_POOL_PATH)?;
+        .open(params.data_path(PENDING_POOL_FILE))?;
     writeln!(file, "{:?}", serialized_transaction)?;
     Ok(())
 }
 
-pub fn delete_last_n_transactions(n: usize) -> Result<(), RitCoinErrror<'static>> {
-    let data = fs::read_to_string(PENDING_POOL_PATH)?;
-    File::create(PENDING_POOL_PATH)?;
-    let mut file = OpenOptions::new()
-        .append(true)
-        .read(true)
-        .open(PENDING_POOL_PATH)?;
-    for tx in data.lines().skip(n) {
+// Drops pending transactions that can no longer be included once the chain changed,
+// like ones spending outputs a newly connected block already spent.
+pub fn remove_invalid_transactions(
+    utxo_set: &UtxoSet,
+    previous_blocks: &[Block],
+    params: &ChainParams,
+) -> Result<(), RitCoinErrror<'static>> {
+    let remaining: Vec<_> = get_last_transactions(None, params)?
+        .into_iter()
+        .filter(|tx| match serializer::deserialize(tx) {
+            Ok(transaction) => {
+                check_transaction(&transaction, utxo_set, previous_blocks, params).is_ok()
+            }
+            Err(_) => false,
+        })
+        .collect();
+    let mut file = File::create(params.data_path(PENDING_POOL_FILE))?;
+    for tx in remaining {
         writeln!(file, "{:?}", tx)?;
     }
     Ok(())
 }
 
+pub fn return_transactions(
+    transactions: &[Vec<u8>],
+    utxo_set: &UtxoSet,
+    previous_blocks: &[Block],
+    params: &ChainParams,
+) {
+    for serialized_transaction in transactions {
+        if let Err(e) =
+            accept_serialized_transaction(serialized_transaction, utxo_set, previous_blocks, params)
+        {
+            println!("Transaction was not returned to mempool: {:?}", e);
+        }
+    }
+}
+
 pub fn accept_serialized_transaction(
     serialized_transaction: &[u8],
     utxo_set: &UtxoSet,
+    previous_blocks: &[Block],
+    params: &ChainParams,
 ) -> Result<(), RitCoinErrror<'static>> {
-    is_saved_already(serialized_transaction)?;
     let transaction = serializer::deserialize(serialized_transaction)?;
-    let pub_keys = transaction.get_pub_keys_from_inputs();
-    let pk_hashes: Vec<_> = pub_keys
-        .iter()
-        .map(|pub_key| wallet::pk_hash_from_public_key(pub_key))
-        .collect();
-    let utxos: Vec<_> = pk_hashes
-        .iter()
-        .map(|pk_hash| utxo_set.by_pkhash(pk_hash))
-        .flatten()
-        .collect();
-    transaction.validate(&utxos)?;
-    save_to_mempool(serialized_transaction)
+    is_saved_already(&transaction, params)?;
+    check_conflicts(&transaction, params)?;
+    check_transaction(&transaction, utxo_set, previous_blocks, params)?;
+    save_to_mempool(serialized_transaction, params)
 }
 
-pub fn is_saved_already(serialized_transaction: &[u8]) -> Result<(), RitCoinErrror<'static>> {
-    for tx in get_last_transactions(None)? {
-        if tx == serialized_transaction {
+// Checks a transaction can be included in the block following previous_blocks.
+pub fn check_transaction(
+    transaction: &Transaction,
+    utxo_set: &UtxoSet,
+    previous_blocks: &[Block],
+    params: &ChainParams,
+) -> Result<(), RitCoinErrror<'static>> {
+    transaction.check_duplicate_inputs()?;
+    transaction.check_output_amounts(params.max_money)?;
+    transaction.check_data_outputs(params.max_data_carrier_size)?;
+    transaction.check_coinbase_maturity(
+        utxo_set,
+        previous_blocks.len() as u32,
+        params.coinbase_maturity,
+    )?;
+    // As in Bitcoin the mempool only holds transactions final in the next block, time locked
+    // ones are rejected and have to be broadcast again once their locks expire. Entries a
+    // reorganization makes non-final again are dropped with the other invalid ones.
+    transaction.check_locks(utxo_set, previous_blocks)?;
+    transaction.validate(utxo_set)
+}
+
+// Rejects a transaction spending an output some pending transaction already spends,
+// otherwise both could be picked into the same block.
+fn check_conflicts(
+    transaction: &Transaction,
+    params: &ChainParams,
+) -> Result<(), RitCoinErrror<'static>> {
+    for pending_transaction in get_last_transactions_deserialized(None, params)? {
+        for input in pending_transaction.get_tx_in() {
+            if transaction
+                .get_tx_in()
+                .iter()
+                .any(|other| other.get_previous_output() == input.get_previous_output())
+            {
+                return Err(RitCoinErrror::from(
+                    "Transaction conflicts with a pending transaction",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn is_saved_already(
+    transaction: &Transaction,
+    params: &ChainParams,
+) -> Result<(), RitCoinErrror<'static>> {
+    let hash = transaction.hash();
+    for tx in get_last_transactions_deserialized(None, params)? {
+        if tx.hash() == hash {
             return Err(RitCoinErrror::from("Tx was already saved to mempool!"));
         }
     }
@@ -69,8 +139,15 @@ pub fn tx_str_to_vec(tx: &str) -> Vec<u8> {
         .collect()
 }
 
-pub fn get_last_transactions(n: Option<usize>) -> Result<Vec<Vec<u8>>, RitCoinErrror<'static>> {
-    let mut input = File::open(PENDING_POOL_PATH)?;
+pub fn get_last_transactions(
+    n: Option<usize>,
+    params: &ChainParams,
+) -> Result<Vec<Vec<u8>>, RitCoinErrror<'static>> {
+    let mut input = match File::open(params.data_path(PENDING_POOL_FILE)) {
+        Ok(input) => input,
+        // A fresh data dir has no mempool file until the first transaction is accepted
+        Err(_) => return Ok(vec![]),
+    };
     input.seek(SeekFrom::Start(0))?;
     let buffered = BufReader::new(input);
     let mut transactions = vec![];
@@ -90,8 +167,9 @@ pub fn get_last_transactions(n: Option<usize>) -> Result<Vec<Vec<u8>>, RitCoinEr
 
 pub fn get_last_transactions_deserialized(
     n: Option<usize>,
+    params: &ChainParams,
 ) -> Result<Vec<Transaction>, RitCoinErrror<'static>> {
-    let serialized_transactions = get_last_transactions(n)?;
+    let serialized_transactions = get_last_transactions(n, params)?;
     let mut deserialized_transactions = vec![];
     for tx in serialized_transactions {
         let deserialized_tx = serializer::deserialize(&tx)?;
@@ -0,0 +1,422 @@
+use crate::chain_params::ChainParams;
+use crate::errors::*;
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, Not, Shl, Shr, Sub};
+
+// Unsigned 256 bit integer, limbs are stored most significant first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub fn zero() -> Self {
+        Self([0; 4])
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Self([0, 0, 0, value])
+    }
+
+    pub fn from_be_bytes(bytes: &[u8]) -> Self {
+        let mut padded = [0; 32];
+        let bytes = &bytes[bytes.len().saturating_sub(32)..];
+        padded[32 - bytes.len()..].copy_from_slice(bytes);
+        let mut limbs = [0; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let mut limb_bytes = [0; 8];
+            limb_bytes.copy_from_slice(&padded[i * 8..i * 8 + 8]);
+            *limb = u64::from_be_bytes(limb_bytes);
+        }
+        Self(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn bits(&self) -> usize {
+        for (i, limb) in self.0.iter().enumerate() {
+            if *limb != 0 {
+                return 64 * (4 - i) - limb.leading_zeros() as usize;
+            }
+        }
+        0
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[3 - index / 64] & (1 << (index % 64)) != 0
+    }
+
+    // Panics on a zero divisor like integer division, the long division below would
+    // otherwise return all ones.
+    pub fn div(self, divisor: Self) -> Self {
+        assert!(divisor != Self::zero(), "attempt to divide by zero");
+        let mut quotient = Self::zero();
+        let mut remainder = Self::zero();
+        for i in (0..self.bits()).rev() {
+            remainder = remainder << 1;
+            if self.bit(i) {
+                remainder.0[3] |= 1;
+            }
+            if remainder >= divisor {
+                remainder = remainder - divisor;
+                quotient.0[3 - i / 64] |= 1 << (i % 64);
+            }
+        }
+        quotient
+    }
+
+    pub fn mul_u64(self, multiplier: u64) -> Self {
+        let mut result = [0; 4];
+        let mut carry = 0_u128;
+        for i in (0..4).rev() {
+            let product = self.0[i] as u128 * multiplier as u128 + carry;
+            result[i] = product as u64;
+            carry = product >> 64;
+        }
+        Self(result)
+    }
+
+    // Decodes the nBits compact representation: 8 bit exponent and 23 bit mantissa,
+    // negative or overflowing values decode to zero which no hash can meet. A mantissa
+    // overflows once any of its bytes would land above the 32nd byte.
+    pub fn from_compact(bits: u32) -> Self {
+        let exponent = (bits >> 24) as usize;
+        let mantissa = bits & 0x007f_ffff;
+        let overflows = mantissa != 0
+            && (exponent > 34
+                || (mantissa > 0xff && exponent > 33)
+                || (mantissa > 0xffff && exponent > 32));
+        if bits & 0x0080_0000 != 0 || overflows {
+            return Self::zero();
+        }
+        if exponent <= 3 {
+            Self::from_u64((mantissa >> (8 * (3 - exponent))) as u64)
+        } else {
+            Self::from_u64(mantissa as u64) << (8 * (exponent - 3))
+        }
+    }
+
+    pub fn to_compact(self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.0[3] << (8 * (3 - size))) as u32
+        } else {
+            (self >> (8 * (size - 3))).0[3] as u32
+        };
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size as u32) << 24
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.to_be_bytes())
+    }
+}
+
+impl Add for U256 {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        let mut result = [0; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (sum, overflow1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, overflow2) = sum.overflowing_add(carry as u64);
+            result[i] = sum;
+            carry = overflow1 || overflow2;
+        }
+        Self(result)
+    }
+}
+
+impl Sub for U256 {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        let mut result = [0; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (difference, overflow1) = self.0[i].overflowing_sub(other.0[i]);
+            let (difference, overflow2) = difference.overflowing_sub(borrow as u64);
+            result[i] = difference;
+            borrow = overflow1 || overflow2;
+        }
+        Self(result)
+    }
+}
+
+impl Not for U256 {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+impl Shl<usize> for U256 {
+    type Output = Self;
+
+    fn shl(self, shift: usize) -> Self {
+        let mut result = [0; 4];
+        let (limb_shift, bit_shift) = (shift / 64, shift % 64);
+        for (i, limb) in result.iter_mut().enumerate() {
+            if i + limb_shift < 4 {
+                *limb |= self.0[i + limb_shift] << bit_shift;
+                if bit_shift != 0 && i + limb_shift + 1 < 4 {
+                    *limb |= self.0[i + limb_shift + 1] >> (64 - bit_shift);
+                }
+            }
+        }
+        Self(result)
+    }
+}
+
+impl Shr<usize> for U256 {
+    type Output = Self;
+
+    fn shr(self, shift: usize) -> Self {
+        let mut result = [0; 4];
+        let (limb_shift, bit_shift) = (shift / 64, shift % 64);
+        for (i, limb) in result.iter_mut().enumerate() {
+            if i >= limb_shift {
+                *limb |= self.0[i - limb_shift] >> bit_shift;
+                if bit_shift != 0 && i > limb_shift {
+                    *limb |= self.0[i - limb_shift - 1] << (64 - bit_shift);
+                }
+            }
+        }
+        Self(result)
+    }
+}
+
+// Scales the previous target by how long the last retarget window actually took,
+// limited to a factor of 4 in either direction and never easier than the pow limit.
+pub fn retarget(params: &ChainParams, bits: u32, actual_timespan: u64) -> u32 {
+    let target_timespan = (params.retarget_interval as u64 - 1) * params.target_block_interval;
+    let actual_timespan = actual_timespan.clamp(target_timespan / 4, target_timespan * 4);
+    let pow_limit = U256::from_compact(params.pow_limit_bits);
+    let target = U256::from_compact(bits)
+        .mul_u64(actual_timespan)
+        .div(U256::from_u64(target_timespan));
+    if target > pow_limit {
+        params.pow_limit_bits
+    } else {
+        target.to_compact()
+    }
+}
+
+// Expected number of hashes needed to find a block meeting the target: 2^256 / (target + 1).
+// Negative or overflowing bits decode to a zero target, which no block can meet, and the
+// maximum target would overflow the divisor, neither counts as work.
+pub fn block_work(target: U256) -> Result<U256, RitCoinErrror<'static>> {
+    if target == U256::zero() || target == !U256::zero() {
+        return Err(RitCoinErrror::from("Invalid block target"));
+    }
+    Ok((!target).div(target + U256::from_u64(1)) + U256::from_u64(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> U256 {
+        U256::from_be_bytes(&hex::decode(hex).unwrap())
+    }
+
+    #[test]
+    fn compact_overflow_decodes_to_zero() {
+        for bits in [
+            0x2300_0001,
+            0x227f_ffff,
+            0x2200_0100,
+            0x2101_0000,
+            0x217f_ffff,
+        ]
+        .iter()
+        {
+            assert_eq!(U256::from_compact(*bits), U256::zero(), "{:x}", bits);
+        }
+        // The largest mantissa that still fits for each exponent
+        assert_eq!(U256::from_compact(0x2200_00ff), U256::from_u64(0xff) << 248);
+        assert_eq!(
+            U256::from_compact(0x2100_ffff),
+            U256::from_u64(0xffff) << 240
+        );
+        assert_eq!(
+            U256::from_compact(0x207f_ffff),
+            U256::from_u64(0x7f_ffff) << 232
+        );
+        // A zero mantissa is zero whatever the exponent
+        assert_eq!(U256::from_compact(0xff00_0000), U256::zero());
+    }
+
+    #[test]
+    fn compact_sign_bit_decodes_to_zero() {
+        assert_eq!(U256::from_compact(0x0492_3456), U256::zero());
+        assert_eq!(U256::from_compact(0x1d80_ffff), U256::zero());
+        // Encoding moves a mantissa with its top bit set into the next byte
+        assert_eq!(U256::from_u64(0x80).to_compact(), 0x0200_8000);
+        assert_eq!(U256::from_compact(0x0200_8000), U256::from_u64(0x80));
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        assert_eq!(
+            U256::from_compact(0x1d00_ffff).to_hex(),
+            "00000000ffff0000000000000000000000000000000000000000000000000000"
+        );
+        for bits in [
+            0x1d00_ffff,
+            0x207f_ffff,
+            0x1f00_ffff,
+            0x1c3f_8663,
+            0x0312_3456,
+            0x0212_3400,
+            0x0112_0000,
+        ]
+        .iter()
+        {
+            assert_eq!(U256::from_compact(*bits).to_compact(), *bits, "{:x}", bits);
+        }
+        // Mantissa bytes below an exponent of 3 are shifted out
+        assert_eq!(U256::from_compact(0x0112_3456), U256::from_u64(0x12));
+        assert_eq!(U256::from_compact(0x0212_3456), U256::from_u64(0x1234));
+        assert_eq!(U256::from_compact(0x0100_3456), U256::zero());
+        assert_eq!(U256::zero().to_compact(), 0);
+    }
+
+    #[test]
+    fn retarget_is_clamped_to_a_factor_of_four() {
+        let params = ChainParams::main();
+        let target_timespan = (params.retarget_interval as u64 - 1) * params.target_block_interval;
+        // Four times the timespan, or more, multiplies the target by four
+        assert_eq!(
+            retarget(&params, 0x1d00_ffff, target_timespan * 4),
+            0x1d03_fffc
+        );
+        assert_eq!(
+            retarget(&params, 0x1d00_ffff, target_timespan * 100),
+            0x1d03_fffc
+        );
+        // A quarter of the timespan, or less, divides it by four
+        let quarter = retarget(&params, 0x1d00_ffff, target_timespan / 4);
+        assert_eq!(quarter, 0x1c3f_8663);
+        assert_eq!(retarget(&params, 0x1d00_ffff, 0), quarter);
+        // The expected timespan keeps the target
+        assert_eq!(retarget(&params, 0x1d00_ffff, target_timespan), 0x1d00_ffff);
+        // Never easier than the pow limit
+        assert_eq!(
+            retarget(&params, 0x1e7f_ffff, target_timespan * 4),
+            params.pow_limit_bits
+        );
+    }
+
+    #[test]
+    fn block_work_known_values() {
+        assert_eq!(
+            block_work(U256::from_compact(0x1d00_ffff)).unwrap(),
+            U256::from_u64(0x1_0001_0001)
+        );
+        // Half of all hashes meet the largest target below 2^255
+        assert_eq!(block_work(!U256::zero() >> 1).unwrap(), U256::from_u64(2));
+        assert_eq!(
+            block_work(U256::from_u64(1)).unwrap(),
+            U256::from_u64(1) << 255
+        );
+        assert!(block_work(U256::zero()).is_err());
+        assert!(block_work(!U256::zero()).is_err());
+        assert!(block_work(U256::from_compact(0x227f_ffff)).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to divide by zero")]
+    fn division_by_zero_panics() {
+        U256::from_u64(1).div(U256::zero());
+    }
+
+    #[test]
+    fn division() {
+        // A divisor larger than the dividend gives zero
+        assert_eq!(U256::from_u64(5).div(U256::from_u64(7)), U256::zero());
+        assert_eq!(
+            (U256::from_u64(1) << 100).div(U256::from_u64(1) << 101),
+            U256::zero()
+        );
+        assert_eq!(U256::from_u64(7).div(U256::from_u64(7)), U256::from_u64(1));
+        let value = from_hex("0123456789abcdef0011223344556677");
+        assert_eq!(value.div(U256::from_u64(1)), value);
+        // Quotients spanning several limbs, against values computed with big integers
+        let dividend = (U256::from_u64(1) << 200) + U256::from_u64(12345);
+        let divisor = (U256::from_u64(1) << 64) + U256::from_u64(1);
+        assert_eq!(
+            dividend.div(divisor),
+            from_hex("ffffffffffffffff000000000000000100")
+        );
+        let dividend = (U256::from_u64(1) << 255) - U256::from_u64(19);
+        let divisor = from_hex("fedcba9876543210fedcba98");
+        assert_eq!(
+            dividend.div(divisor),
+            from_hex("8092492492492491bf6343eb55d0fac71b6e75ec")
+        );
+    }
+
+    #[test]
+    fn multiplication_carries_across_limbs() {
+        let value = (U256::from_u64(0x0123_4567_89ab_cdef) << 192)
+            + (U256::from_u64(u64::MAX) << 64)
+            + U256::from_u64(5);
+        // Bits above 2^256 are dropped
+        assert_eq!(
+            value.mul_u64(0xfedc_ba98_7654_3210),
+            from_hex("2236d88fe5618cf0fedcba987654320f0123456789abcdf4fa4fa4fa4fa4fa50")
+        );
+        assert_eq!(
+            U256::from_u64(u64::MAX).mul_u64(u64::MAX),
+            from_hex("fffffffffffffffe0000000000000001")
+        );
+    }
+
+    #[test]
+    fn shifts_and_bits() {
+        assert_eq!(U256::from_u64(1) << 64, from_hex("010000000000000000"));
+        assert_eq!(
+            from_hex("0123456789abcdef0011223344556677") >> 4,
+            from_hex("00123456789abcdef001122334455667")
+        );
+        assert_eq!(
+            from_hex("0123456789abcdef0011223344556677") << 68,
+            from_hex("123456789abcdef001122334455667700000000000000000")
+        );
+        let value = from_hex("0123456789abcdef0011223344556677");
+        assert_eq!((value << 100) >> 100, value);
+        assert_eq!(U256::from_u64(1) << 256, U256::zero());
+        assert_eq!(!U256::zero() >> 256, U256::zero());
+        assert_eq!(U256::zero().bits(), 0);
+        assert_eq!(U256::from_u64(1).bits(), 1);
+        assert_eq!((U256::from_u64(1) << 64).bits(), 65);
+        assert_eq!((U256::from_u64(1) << 255).bits(), 256);
+        assert_eq!(
+            !U256::zero() >> 1,
+            (U256::from_u64(1) << 255) - U256::from_u64(1)
+        );
+    }
+
+    #[test]
+    fn addition_and_subtraction_carry() {
+        assert_eq!(
+            U256::from_u64(u64::MAX) + U256::from_u64(1),
+            U256::from_u64(1) << 64
+        );
+        assert_eq!(
+            (U256::from_u64(1) << 128) - U256::from_u64(1),
+            from_hex("ffffffffffffffffffffffffffffffff")
+        );
+        assert_eq!(!U256::zero() + U256::from_u64(1), U256::zero());
+    }
+}
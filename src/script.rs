@@ -1,8 +1,289 @@
 use crate::errors::*;
+use crate::hash::*;
 use crate::opcodes::*;
+use crate::serializer::HASH_SIZE;
+use crate::transaction::Transaction;
 use crate::wallet;
 use std::ops::{Deref, DerefMut};
 
+const MAX_SCRIPT_SIZE: usize = 10_000;
+const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+const MAX_OPS_PER_SCRIPT: usize = 201;
+const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+// Numeric operands are limited to 4 bytes, results may overflow into a fifth.
+const MAX_SCRIPT_NUM_LENGTH: usize = 4;
+// Lock times and sequences are unsigned 32 bit values, so they may take 5 bytes.
+const MAX_LOCK_NUM_LENGTH: usize = 5;
+
+pub const HTLC_SECRET_SIZE: usize = 32;
+
+// Disabled in Bitcoin, scripts containing them fail even in unexecuted branches.
+const DISABLED_OPCODES: [u8; 15] = [
+    OP_CAT, OP_SUBSTR, OP_LEFT, OP_RIGHT, OP_INVERT, OP_AND, OP_OR, OP_XOR, OP_2MUL, OP_2DIV,
+    OP_MUL, OP_DIV, OP_MOD, OP_LSHIFT, OP_RSHIFT,
+];
+
+pub enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+pub struct Instructions<'a> {
+    script: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(script: &'a [u8]) -> Self {
+        Self {
+            script,
+            position: 0,
+        }
+    }
+
+    // Offset of the next instruction in the script.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], RitCoinErrror<'static>> {
+        if self.script.len() - self.position < len {
+            self.position = self.script.len();
+            return Err(RitCoinErrror::from("Push exceeds script"));
+        }
+        let bytes = &self.script[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn take_push(&mut self, opcode: u8) -> Result<Instruction<'a>, RitCoinErrror<'static>> {
+        let len = match opcode {
+            OP_PUSHDATA1 => self.take(1)?[0] as usize,
+            OP_PUSHDATA2 => {
+                let len = self.take(2)?;
+                u16::from_le_bytes([len[0], len[1]]) as usize
+            }
+            OP_PUSHDATA4 => {
+                let len = self.take(4)?;
+                u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize
+            }
+            len => len as usize,
+        };
+        Ok(Instruction::Push(self.take(len)?))
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, RitCoinErrror<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let opcode = *self.script.get(self.position)?;
+        self.position += 1;
+        if opcode <= OP_PUSHDATA4 {
+            Some(self.take_push(opcode))
+        } else {
+            Some(Ok(Instruction::Op(opcode)))
+        }
+    }
+}
+
+// Appends the shortest push of data to a script.
+pub fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    match data.len() {
+        len if len < OP_PUSHDATA1 as usize => script.push(len as u8),
+        len if len <= 0xff => script.extend_from_slice(&[OP_PUSHDATA1, len as u8]),
+        len if len <= 0xffff => {
+            script.push(OP_PUSHDATA2);
+            script.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+        len => {
+            script.push(OP_PUSHDATA4);
+            script.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+    }
+    script.extend_from_slice(data);
+}
+
+pub fn small_int_opcode(value: usize) -> u8 {
+    match value {
+        0 => OP_0,
+        value => OP_1 + value as u8 - 1,
+    }
+}
+
+pub fn decode_small_int(opcode: u8) -> Option<usize> {
+    match opcode {
+        OP_1..=OP_16 => Some((opcode - OP_1) as usize + 1),
+        _ => None,
+    }
+}
+
+// Number of required signatures and the public keys of an m-of-n multisig script.
+pub fn get_multisig(script: &[u8]) -> Option<(usize, Vec<&[u8]>)> {
+    let mut instructions = vec![];
+    for instruction in Instructions::new(script) {
+        instructions.push(instruction.ok()?);
+    }
+    match instructions.as_slice() {
+        [Instruction::Op(required), keys @ .., Instruction::Op(keys_count), Instruction::Op(OP_CHECKMULTISIG)] =>
+        {
+            let required = decode_small_int(*required)?;
+            let mut public_keys = vec![];
+            for key in keys {
+                match key {
+                    Instruction::Push(public_key) => public_keys.push(*public_key),
+                    Instruction::Op(_) => return None,
+                }
+            }
+            if decode_small_int(*keys_count)? == public_keys.len() && required <= public_keys.len()
+            {
+                Some((required, public_keys))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// Secret hash, recipient pubkey hash, lock time and refund pubkey hash of an HTLC script.
+pub type Htlc<'a> = (&'a [u8], &'a [u8], u32, &'a [u8]);
+
+pub fn get_htlc(script: &[u8]) -> Option<Htlc<'_>> {
+    let mut instructions = vec![];
+    for instruction in Instructions::new(script) {
+        instructions.push(instruction.ok()?);
+    }
+    match instructions.as_slice() {
+        [Instruction::Op(OP_IF), Instruction::Op(OP_SIZE), Instruction::Push(secret_size), Instruction::Op(OP_EQUALVERIFY), Instruction::Op(OP_SHA256), Instruction::Push(secret_hash), Instruction::Op(OP_EQUALVERIFY), Instruction::Op(OP_DUP), Instruction::Op(OP_HASH160), Instruction::Push(recipient_pkhash), Instruction::Op(OP_ELSE), Instruction::Push(lock_time), Instruction::Op(OP_CHECKLOCKTIMEVERIFY), Instruction::Op(OP_DROP), Instruction::Op(OP_DUP), Instruction::Op(OP_HASH160), Instruction::Push(refund_pkhash), Instruction::Op(OP_ENDIF), Instruction::Op(OP_EQUALVERIFY), Instruction::Op(OP_CHECKSIG)]
+            if decode_num(secret_size).ok()? == HTLC_SECRET_SIZE as i64
+                && secret_hash.len() == HASH_SIZE
+                && recipient_pkhash.len() == 20
+                && refund_pkhash.len() == 20 =>
+        {
+            let lock_time = decode_num_with_length(lock_time, MAX_LOCK_NUM_LENGTH).ok()?;
+            if lock_time < 0 || lock_time > u32::MAX as i64 {
+                return None;
+            }
+            Some((
+                secret_hash,
+                recipient_pkhash,
+                lock_time as u32,
+                refund_pkhash,
+            ))
+        }
+        _ => None,
+    }
+}
+
+// The secret revealed by a signature script claiming a P2SH HTLC output:
+// <signature> <public key> <secret> OP_1 <redeem script>
+pub fn get_htlc_secret(sig_script: &[u8]) -> Option<&[u8]> {
+    let mut instructions = vec![];
+    for instruction in Instructions::new(sig_script) {
+        instructions.push(instruction.ok()?);
+    }
+    match instructions.as_slice() {
+        [Instruction::Push(_), Instruction::Push(_), Instruction::Push(secret), Instruction::Op(OP_1), Instruction::Push(redeem_script)] =>
+        {
+            let (secret_hash, ..) = get_htlc(redeem_script)?;
+            if sha256(secret) == secret_hash {
+                Some(secret)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// Outputs starting with OP_RETURN can never be spent, neither can oversized scripts.
+pub fn is_unspendable(script: &[u8]) -> bool {
+    script.first() == Some(&OP_RETURN) || script.len() > MAX_SCRIPT_SIZE
+}
+
+// The payload of a data carrier script: OP_RETURN <data>
+pub fn get_data(script: &[u8]) -> Option<&[u8]> {
+    match script.split_first() {
+        Some((&OP_RETURN, rest)) => {
+            let mut instructions = Instructions::new(rest);
+            match (instructions.next()?, instructions.next()) {
+                (Ok(Instruction::Push(data)), None) => Some(data),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// OP_HASH160 <20 byte script hash> OP_EQUAL
+pub fn is_p2sh(script: &[u8]) -> bool {
+    script.len() == 23 && script[..2] == [OP_HASH160, 0x14] && script[22] == OP_EQUAL
+}
+
+pub fn is_push_only(script: &[u8]) -> Result<bool, RitCoinErrror<'static>> {
+    for instruction in Instructions::new(script) {
+        if let Instruction::Op(opcode) = instruction? {
+            if opcode > OP_16 {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Numbers are little-endian sign and magnitude, the sign is the top bit of the last byte.
+pub fn decode_num(bytes: &[u8]) -> Result<i64, RitCoinErrror<'static>> {
+    decode_num_with_length(bytes, MAX_SCRIPT_NUM_LENGTH)
+}
+
+fn decode_num_with_length(bytes: &[u8], max_length: usize) -> Result<i64, RitCoinErrror<'static>> {
+    if bytes.len() > max_length {
+        return Err(RitCoinErrror::from("Script number overflow"));
+    }
+    let magnitude = bytes
+        .iter()
+        .enumerate()
+        .fold(0_i64, |acc, (i, byte)| acc | (*byte as i64) << (8 * i));
+    match bytes.last() {
+        Some(last) if last & 0x80 != 0 => Ok(-(magnitude & !(0x80 << (8 * (bytes.len() - 1))))),
+        _ => Ok(magnitude),
+    }
+}
+
+pub fn encode_num(value: i64) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut magnitude = value.unsigned_abs();
+    while magnitude > 0 {
+        bytes.push(magnitude as u8);
+        magnitude >>= 8;
+    }
+    let sign = if value < 0 { 0x80 } else { 0 };
+    match bytes.last_mut() {
+        Some(last) if *last & 0x80 == 0 => *last |= sign,
+        Some(_) => bytes.push(sign),
+        None => (),
+    }
+    bytes
+}
+
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        Some((last, rest)) => rest.iter().any(|byte| *byte != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+#[derive(Clone)]
 struct Stack(Vec<Vec<u8>>);
 
 impl Deref for Stack {
@@ -20,96 +301,752 @@ impl DerefMut for Stack {
 }
 
 impl Stack {
-    fn new(sig_script: &[u8]) -> Self {
-        let mut sig_script = sig_script.to_vec();
-        match sig_script.len() {
-            138 => {
-                //remove sig length
-                sig_script.remove(0);
-                //remove sig type
-                sig_script.remove(70);
-                //remove pub_key len
-                sig_script.remove(70);
-            }
-            139 => {
-                //remove sig length
-                sig_script.remove(0);
-                //remove sig type
-                sig_script.remove(71);
-                //remove pub_key len
-                sig_script.remove(71);
-            }
-            _ => (),
-        }
-        Self(vec![
-            sig_script[..sig_script.len() - 65].to_owned(),
-            sig_script[sig_script.len() - 65..].to_owned(),
-        ])
-    }
-
-    fn op_dup(&mut self) -> Result<(), RitCoinErrror<'static>> {
-        if let Some(last) = self.last() {
-            let last = last.clone();
-            self.push(last);
-            Ok(())
+    fn require(&self, count: usize) -> Result<(), RitCoinErrror<'static>> {
+        if self.len() < count {
+            Err(RitCoinErrror::from("Stack has too few elements"))
         } else {
-            Err(RitCoinErrror::from("Stack must contain at least 1 element"))
+            Ok(())
         }
     }
 
-    fn op_hash160(&mut self) -> Result<(), RitCoinErrror<'static>> {
-        if let Some(last) = self.pop() {
-            let hash = wallet::pk_hash_from_public_key(&last);
-            self.push(hash);
+    // Element at depth counted from the top, the top itself is at depth 0.
+    fn peek(&self, depth: usize) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+        self.require(depth + 1)?;
+        Ok(self[self.len() - 1 - depth].clone())
+    }
+
+    fn pop_element(&mut self) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+        self.pop()
+            .ok_or_else(|| RitCoinErrror::from("Stack has too few elements"))
+    }
+
+    fn pop_elements(&mut self, count: usize) -> Result<Vec<Vec<u8>>, RitCoinErrror<'static>> {
+        self.require(count)?;
+        let index = self.len() - count;
+        Ok(self.split_off(index))
+    }
+
+    fn pop_num(&mut self) -> Result<i64, RitCoinErrror<'static>> {
+        decode_num(&self.pop_element()?)
+    }
+
+    // CHECKLOCKTIMEVERIFY and CHECKSEQUENCEVERIFY leave their operand on the stack. A 5 byte
+    // number can be negative or exceed u32::MAX, either way the script fails.
+    fn peek_lock_num(&self) -> Result<u32, RitCoinErrror<'static>> {
+        let value = decode_num_with_length(&self.peek(0)?, MAX_LOCK_NUM_LENGTH)?;
+        if value < 0 {
+            return Err(RitCoinErrror::from("Negative lock time in script"));
+        }
+        if value > u32::MAX as i64 {
+            return Err(RitCoinErrror::from("Lock time in script out of range"));
+        }
+        Ok(value as u32)
+    }
+
+    fn pop_bool(&mut self) -> Result<bool, RitCoinErrror<'static>> {
+        Ok(cast_to_bool(&self.pop_element()?))
+    }
+
+    fn op_verify(&mut self) -> Result<(), RitCoinErrror<'static>> {
+        if self.pop_bool()? {
             Ok(())
         } else {
-            Err(RitCoinErrror::from("Stack must contain at least 1 element"))
+            Err(RitCoinErrror::from("Script verification failed"))
+        }
+    }
+
+    // Moves the element at depth to the top, copying it unless remove is set.
+    fn op_pick(&mut self, remove: bool) -> Result<(), RitCoinErrror<'static>> {
+        let depth = self.pop_num()?;
+        if depth < 0 || depth as usize >= self.len() {
+            return Err(RitCoinErrror::from("Pick or roll depth out of range"));
         }
+        let index = self.len() - 1 - depth as usize;
+        let element = if remove {
+            self.remove(index)
+        } else {
+            self[index].clone()
+        };
+        self.push(element);
+        Ok(())
     }
 
-    fn op_equalverify(&mut self) -> Result<(), RitCoinErrror<'static>> {
-        match (self.pop(), self.pop()) {
-            (Some(a), Some(b)) => {
-                if a == b {
-                    Ok(())
-                } else {
-                    Err(RitCoinErrror::from("pkhashes didn`t match"))
+    fn op_unary_num(
+        &mut self,
+        operation: impl Fn(i64) -> i64,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let a = self.pop_num()?;
+        self.push(encode_num(operation(a)));
+        Ok(())
+    }
+
+    fn op_binary_num(
+        &mut self,
+        operation: impl Fn(i64, i64) -> i64,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let b = self.pop_num()?;
+        let a = self.pop_num()?;
+        self.push(encode_num(operation(a, b)));
+        Ok(())
+    }
+
+    fn op_hash(&mut self, hash: impl Fn(&[u8]) -> Vec<u8>) -> Result<(), RitCoinErrror<'static>> {
+        let element = self.pop_element()?;
+        self.push(hash(&element));
+        Ok(())
+    }
+
+    // An empty signature fails softly, leaving false on the stack, any other signature
+    // that does not verify fails the script.
+    fn op_check_sig(
+        &mut self,
+        script_code: &[u8],
+        context: &TxContext,
+    ) -> Result<bool, RitCoinErrror<'static>> {
+        let public_key = self.pop_element()?;
+        let signature = self.pop_element()?;
+        match signature.split_last() {
+            Some((sighash_type, signature)) => {
+                let hash = context.signature_hash(script_code, *sighash_type)?;
+                wallet::verify(&hash, signature, &public_key)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Takes n keys, m signatures and one extra element that has to be empty, as the
+    // original Bitcoin implementation pops one element too many. Signatures must be in
+    // the same order as their keys. A failed check with any non-empty signature fails
+    // the script.
+    fn op_check_multisig(
+        &mut self,
+        script_code: &[u8],
+        context: &TxContext,
+        op_count: &mut usize,
+    ) -> Result<bool, RitCoinErrror<'static>> {
+        let keys_count = self.pop_num()?;
+        if keys_count < 0 || keys_count as usize > MAX_PUBKEYS_PER_MULTISIG {
+            return Err(RitCoinErrror::from("Invalid multisig public key count"));
+        }
+        *op_count += keys_count as usize;
+        if *op_count > MAX_OPS_PER_SCRIPT {
+            return Err(RitCoinErrror::from("Script exceeds operation limit"));
+        }
+        let public_keys = self.pop_elements(keys_count as usize)?;
+        let signatures_count = self.pop_num()?;
+        if signatures_count < 0 || signatures_count > keys_count {
+            return Err(RitCoinErrror::from("Invalid multisig signature count"));
+        }
+        let signatures = self.pop_elements(signatures_count as usize)?;
+        if !self.pop_element()?.is_empty() {
+            return Err(RitCoinErrror::from("Multisig dummy element must be empty"));
+        }
+        let mut public_keys = public_keys.iter();
+        let mut valid = true;
+        for signature in &signatures {
+            valid = match signature.split_last() {
+                Some((sighash_type, signature)) => {
+                    let hash = context.signature_hash(script_code, *sighash_type)?;
+                    public_keys
+                        .any(|public_key| wallet::verify(&hash, signature, public_key).is_ok())
+                }
+                None => false,
+            };
+            if !valid {
+                break;
+            }
+        }
+        if !valid && signatures.iter().any(|signature| !signature.is_empty()) {
+            return Err(RitCoinErrror::from(
+                "Multisig signature verification failed",
+            ));
+        }
+        Ok(valid)
+    }
+
+    fn execute_opcode(
+        &mut self,
+        alt_stack: &mut Stack,
+        opcode: u8,
+        script_code: &[u8],
+        context: &TxContext,
+        op_count: &mut usize,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        match opcode {
+            OP_1NEGATE => self.push(encode_num(-1)),
+            OP_1..=OP_16 => self.push(encode_num((opcode - OP_1 + 1) as i64)),
+            OP_NOP | OP_NOP1 | OP_NOP4..=OP_NOP10 => (),
+            OP_VERIFY => self.op_verify()?,
+            OP_RETURN => return Err(RitCoinErrror::from("Script executed OP_RETURN")),
+            OP_CHECKLOCKTIMEVERIFY => {
+                if !context
+                    .tx
+                    .check_lock_time(context.input_index, self.peek_lock_num()?)
+                {
+                    return Err(RitCoinErrror::from("Lock time requirement not satisfied"));
+                }
+            }
+            OP_CHECKSEQUENCEVERIFY => {
+                if !context
+                    .tx
+                    .check_sequence(context.input_index, self.peek_lock_num()?)
+                {
+                    return Err(RitCoinErrror::from("Sequence requirement not satisfied"));
+                }
+            }
+
+            OP_TOALTSTACK => {
+                let element = self.pop_element()?;
+                alt_stack.push(element);
+            }
+            OP_FROMALTSTACK => {
+                let element = alt_stack.pop_element()?;
+                self.push(element);
+            }
+            OP_2DROP => {
+                self.require(2)?;
+                let len = self.len();
+                self.truncate(len - 2);
+            }
+            OP_2DUP => {
+                let (a, b) = (self.peek(1)?, self.peek(0)?);
+                self.extend(vec![a, b]);
+            }
+            OP_3DUP => {
+                let (a, b, c) = (self.peek(2)?, self.peek(1)?, self.peek(0)?);
+                self.extend(vec![a, b, c]);
+            }
+            OP_2OVER => {
+                let (a, b) = (self.peek(3)?, self.peek(2)?);
+                self.extend(vec![a, b]);
+            }
+            OP_2ROT => {
+                self.require(6)?;
+                let index = self.len() - 6;
+                let pair: Vec<Vec<u8>> = self.drain(index..index + 2).collect();
+                self.extend(pair);
+            }
+            OP_2SWAP => {
+                self.require(4)?;
+                let len = self.len();
+                self.swap(len - 4, len - 2);
+                self.swap(len - 3, len - 1);
+            }
+            OP_IFDUP => {
+                let top = self.peek(0)?;
+                if cast_to_bool(&top) {
+                    self.push(top);
+                }
+            }
+            OP_DEPTH => {
+                let depth = self.len() as i64;
+                self.push(encode_num(depth));
+            }
+            OP_DROP => {
+                self.pop_element()?;
+            }
+            OP_DUP => {
+                let top = self.peek(0)?;
+                self.push(top);
+            }
+            OP_NIP => {
+                self.require(2)?;
+                let index = self.len() - 2;
+                self.remove(index);
+            }
+            OP_OVER => {
+                let second = self.peek(1)?;
+                self.push(second);
+            }
+            OP_PICK => self.op_pick(false)?,
+            OP_ROLL => self.op_pick(true)?,
+            OP_ROT => {
+                self.require(3)?;
+                let index = self.len() - 3;
+                let element = self.remove(index);
+                self.push(element);
+            }
+            OP_SWAP => {
+                self.require(2)?;
+                let len = self.len();
+                self.swap(len - 2, len - 1);
+            }
+            OP_TUCK => {
+                self.require(2)?;
+                let top = self.peek(0)?;
+                let index = self.len() - 2;
+                self.insert(index, top);
+            }
+            OP_SIZE => {
+                let size = self.peek(0)?.len() as i64;
+                self.push(encode_num(size));
+            }
+
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let (b, a) = (self.pop_element()?, self.pop_element()?);
+                self.push(encode_bool(a == b));
+                if opcode == OP_EQUALVERIFY {
+                    self.op_verify()?;
+                }
+            }
+
+            OP_1ADD => self.op_unary_num(|a| a + 1)?,
+            OP_1SUB => self.op_unary_num(|a| a - 1)?,
+            OP_NEGATE => self.op_unary_num(|a| -a)?,
+            OP_ABS => self.op_unary_num(|a| a.abs())?,
+            OP_NOT => self.op_unary_num(|a| (a == 0) as i64)?,
+            OP_0NOTEQUAL => self.op_unary_num(|a| (a != 0) as i64)?,
+            OP_ADD => self.op_binary_num(|a, b| a + b)?,
+            OP_SUB => self.op_binary_num(|a, b| a - b)?,
+            OP_BOOLAND => self.op_binary_num(|a, b| (a != 0 && b != 0) as i64)?,
+            OP_BOOLOR => self.op_binary_num(|a, b| (a != 0 || b != 0) as i64)?,
+            OP_NUMEQUAL => self.op_binary_num(|a, b| (a == b) as i64)?,
+            OP_NUMEQUALVERIFY => {
+                self.op_binary_num(|a, b| (a == b) as i64)?;
+                self.op_verify()?;
+            }
+            OP_NUMNOTEQUAL => self.op_binary_num(|a, b| (a != b) as i64)?,
+            OP_LESSTHAN => self.op_binary_num(|a, b| (a < b) as i64)?,
+            OP_GREATERTHAN => self.op_binary_num(|a, b| (a > b) as i64)?,
+            OP_LESSTHANOREQUAL => self.op_binary_num(|a, b| (a <= b) as i64)?,
+            OP_GREATERTHANOREQUAL => self.op_binary_num(|a, b| (a >= b) as i64)?,
+            OP_MIN => self.op_binary_num(|a, b| a.min(b))?,
+            OP_MAX => self.op_binary_num(|a, b| a.max(b))?,
+            OP_WITHIN => {
+                let max = self.pop_num()?;
+                let min = self.pop_num()?;
+                let value = self.pop_num()?;
+                self.push(encode_bool(min <= value && value < max));
+            }
+
+            OP_RIPEMD160 => self.op_hash(ripemd160)?,
+            OP_SHA1 => self.op_hash(sha1)?,
+            OP_SHA256 => self.op_hash(sha256)?,
+            OP_HASH160 => self.op_hash(hash160)?,
+            OP_HASH256 => self.op_hash(hash256)?,
+            OP_CHECKSIG => {
+                let valid = self.op_check_sig(script_code, context)?;
+                self.push(encode_bool(valid));
+            }
+            OP_CHECKSIGVERIFY => {
+                if !self.op_check_sig(script_code, context)? {
+                    return Err(RitCoinErrror::from("Signature verification failed"));
+                }
+            }
+            OP_CHECKMULTISIG => {
+                let valid = self.op_check_multisig(script_code, context, op_count)?;
+                self.push(encode_bool(valid));
+            }
+            OP_CHECKMULTISIGVERIFY => {
+                if !self.op_check_multisig(script_code, context, op_count)? {
+                    return Err(RitCoinErrror::from("Signature verification failed"));
                 }
             }
-            _ => Err(RitCoinErrror::from("pkhash not found")),
+            _ => return Err(RitCoinErrror::from("Unknown or unsupported opcode")),
         }
+        Ok(())
     }
 
-    fn op_check_sig(&mut self, hash: &[u8]) -> Result<(), RitCoinErrror<'static>> {
-        let public_key = self.pop();
-        let signature = self.pop();
-        match (public_key, signature) {
-            (Some(public_key), Some(signature)) => {
-                Ok(wallet::verify(hash, &signature, &public_key)?)
+    // Runs a script on the stack. Data pushes and OP_1NEGATE..OP_16 are free, every
+    // other opcode counts towards the operation limit whether or not it is executed.
+    fn run(&mut self, script: &[u8], context: &TxContext) -> Result<(), RitCoinErrror<'static>> {
+        if script.len() > MAX_SCRIPT_SIZE {
+            return Err(RitCoinErrror::from("Script is too large"));
+        }
+        let mut alt_stack = Stack(vec![]);
+        let mut conditions: Vec<bool> = vec![];
+        let mut op_count = 0;
+        let mut code_start = 0;
+        let mut instructions = Instructions::new(script);
+        while let Some(instruction) = instructions.next() {
+            let executing = conditions.iter().all(|condition| *condition);
+            match instruction? {
+                Instruction::Push(data) => {
+                    if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                        return Err(RitCoinErrror::from("Pushed element is too large"));
+                    }
+                    if executing {
+                        self.push(data.to_vec());
+                    }
+                }
+                Instruction::Op(opcode) => {
+                    if opcode > OP_16 {
+                        op_count += 1;
+                        if op_count > MAX_OPS_PER_SCRIPT {
+                            return Err(RitCoinErrror::from("Script exceeds operation limit"));
+                        }
+                    }
+                    if DISABLED_OPCODES.contains(&opcode)
+                        || opcode == OP_VERIF
+                        || opcode == OP_VERNOTIF
+                    {
+                        return Err(RitCoinErrror::from("Script contains a disabled opcode"));
+                    }
+                    match opcode {
+                        OP_IF | OP_NOTIF => {
+                            let condition = executing && self.pop_bool()? == (opcode == OP_IF);
+                            conditions.push(condition);
+                        }
+                        OP_ELSE => match conditions.last_mut() {
+                            Some(condition) => *condition = !*condition,
+                            None => return Err(RitCoinErrror::from("OP_ELSE without OP_IF")),
+                        },
+                        OP_ENDIF => {
+                            conditions
+                                .pop()
+                                .ok_or_else(|| RitCoinErrror::from("OP_ENDIF without OP_IF"))?;
+                        }
+                        OP_CODESEPARATOR if executing => code_start = instructions.position(),
+                        opcode if executing => self.execute_opcode(
+                            &mut alt_stack,
+                            opcode,
+                            &script[code_start..],
+                            context,
+                            &mut op_count,
+                        )?,
+                        _ => (),
+                    }
+                }
+            }
+            if self.len() + alt_stack.len() > MAX_STACK_SIZE {
+                return Err(RitCoinErrror::from("Stack size limit exceeded"));
             }
-            _ => Err(RitCoinErrror::from("Public key or signature not found!")),
         }
+        if conditions.is_empty() {
+            Ok(())
+        } else {
+            Err(RitCoinErrror::from("Unbalanced conditional in script"))
+        }
+    }
+}
+
+// The spending transaction and the index of the input whose script is executed.
+pub struct TxContext<'a> {
+    tx: &'a Transaction,
+    input_index: usize,
+}
+
+impl<'a> TxContext<'a> {
+    pub fn new(tx: &'a Transaction, input_index: usize) -> Self {
+        Self { tx, input_index }
+    }
+
+    fn signature_hash(
+        &self,
+        script_code: &[u8],
+        sighash_type: u8,
+    ) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+        self.tx
+            .signature_hash(self.input_index, script_code, sighash_type)
     }
 }
-//TODO More efficient and complete script
+
+fn check_result(stack: &Stack) -> Result<(), RitCoinErrror<'static>> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(RitCoinErrror::from("Script evaluated to false")),
+    }
+}
+
+// The signature script may only push data. It runs first and the script pubkey runs on
+// the resulting stack, the input is valid if the top element is then true. For a P2SH
+// script pubkey the last push of the signature script is the redeem script, it must
+// match the hash and then runs on the rest of the pushed elements.
 pub fn execute(
     sig_script: &[u8],
     script_pubkey: &[u8],
-    hash: &[u8],
+    context: &TxContext,
 ) -> Result<(), RitCoinErrror<'static>> {
-    let mut stack = Stack::new(sig_script);
-    let mut pk_hash = vec![];
-    for opcode in script_pubkey {
-        match *opcode {
-            OP_DUP => stack.op_dup()?,
-            OP_HASH160 => stack.op_hash160()?,
-            OP_EQUALVERIFY => {
-                stack.push(pk_hash.clone());
-                stack.op_equalverify()?
-            }
-            OP_CHECKSIG => stack.op_check_sig(hash)?,
-            opcode => pk_hash.push(opcode),
-        }
+    if !is_push_only(sig_script)? {
+        return Err(RitCoinErrror::from("Signature script must only push data"));
+    }
+    let mut stack = Stack(vec![]);
+    stack.run(sig_script, context)?;
+    let redeem_stack = if is_p2sh(script_pubkey) {
+        Some(stack.clone())
+    } else {
+        None
+    };
+    stack.run(script_pubkey, context)?;
+    check_result(&stack)?;
+    if let Some(mut redeem_stack) = redeem_stack {
+        let redeem_script = redeem_stack.pop_element()?;
+        redeem_stack.run(&redeem_script, context)?;
+        check_result(&redeem_stack)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::error_message;
+    use crate::transaction::{Input, Output};
+    use crate::utxo_set::Utxo;
+
+    fn spending_transaction(script_pubkey: &[u8]) -> Transaction {
+        let output = Output::new(10, script_pubkey.to_vec());
+        let utxo = Utxo::new(vec![1; HASH_SIZE], 0, output, 1, false);
+        Transaction::new(
+            vec![Input::create(&utxo)],
+            vec![Output::create(9, &[2; 20])],
+        )
+    }
+
+    fn run_script(script: &[u8]) -> Result<Vec<Vec<u8>>, RitCoinErrror<'static>> {
+        let transaction = spending_transaction(&[]);
+        let mut stack = Stack(vec![]);
+        stack.run(script, &TxContext::new(&transaction, 0))?;
+        Ok(stack.0)
+    }
+
+    #[test]
+    fn push_opcodes() {
+        let script = [
+            OP_0,
+            0x02,
+            0xaa,
+            0xbb,
+            OP_PUSHDATA1,
+            0x01,
+            0xcc,
+            OP_PUSHDATA2,
+            0x01,
+            0x00,
+            0xdd,
+            OP_PUSHDATA4,
+            0x01,
+            0x00,
+            0x00,
+            0x00,
+            0xee,
+            OP_1NEGATE,
+            OP_16,
+        ];
+        let expected = vec![
+            vec![],
+            vec![0xaa, 0xbb],
+            vec![0xcc],
+            vec![0xdd],
+            vec![0xee],
+            vec![0x81],
+            vec![0x10],
+        ];
+        assert_eq!(run_script(&script).unwrap(), expected);
+        assert_eq!(
+            error_message(run_script(&[OP_PUSHDATA1, 0x02, 0xaa])),
+            "Push exceeds script"
+        );
+        assert_eq!(
+            error_message(run_script(&[OP_PUSHDATA2, 0x01])),
+            "Push exceeds script"
+        );
+    }
+
+    #[test]
+    fn push_data_uses_the_shortest_push() {
+        for (len, prefix) in [(0, 1), (75, 1), (76, 2), (255, 2), (256, 3), (520, 3)].iter() {
+            let mut script = vec![];
+            push_data(&mut script, &vec![0xab; *len]);
+            assert_eq!(script.len(), prefix + len);
+            assert_eq!(run_script(&script).unwrap(), vec![vec![0xab; *len]]);
+        }
+        let mut script = vec![];
+        push_data(&mut script, &[0; MAX_SCRIPT_ELEMENT_SIZE + 1]);
+        assert_eq!(
+            error_message(run_script(&script)),
+            "Pushed element is too large"
+        );
+    }
+
+    #[test]
+    fn if_else_nesting() {
+        let script = [
+            OP_1, OP_IF, OP_0, OP_IF, OP_2, OP_ELSE, OP_3, OP_ENDIF, OP_ELSE, OP_4, OP_ENDIF,
+        ];
+        assert_eq!(run_script(&script).unwrap(), vec![vec![3]]);
+        let script = [
+            OP_0, OP_IF, OP_1, OP_IF, OP_2, OP_ENDIF, OP_ELSE, OP_0, OP_NOTIF, OP_5, OP_ENDIF,
+            OP_ENDIF,
+        ];
+        assert_eq!(run_script(&script).unwrap(), vec![vec![5]]);
+        // Every OP_ELSE flips the branch again
+        let script = [OP_1, OP_IF, OP_2, OP_ELSE, OP_3, OP_ELSE, OP_4, OP_ENDIF];
+        assert_eq!(run_script(&script).unwrap(), vec![vec![2], vec![4]]);
+        // Unexecuted branches do not pop their condition
+        let script = [OP_0, OP_IF, OP_IF, OP_ENDIF, OP_ENDIF];
+        assert_eq!(run_script(&script).unwrap(), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn unbalanced_conditionals_fail() {
+        assert_eq!(
+            error_message(run_script(&[OP_1, OP_IF])),
+            "Unbalanced conditional in script"
+        );
+        assert_eq!(
+            error_message(run_script(&[OP_ELSE])),
+            "OP_ELSE without OP_IF"
+        );
+        assert_eq!(
+            error_message(run_script(&[OP_1, OP_ENDIF])),
+            "OP_ENDIF without OP_IF"
+        );
+        assert_eq!(
+            error_message(run_script(&[OP_IF, OP_ENDIF])),
+            "Stack has too few elements"
+        );
+    }
+
+    #[test]
+    fn disabled_opcodes_fail_in_unexecuted_branches() {
+        assert_eq!(
+            error_message(run_script(&[OP_0, OP_IF, OP_CAT, OP_ENDIF])),
+            "Script contains a disabled opcode"
+        );
+        assert_eq!(
+            error_message(run_script(&[OP_0, OP_IF, OP_VERIF, OP_ENDIF])),
+            "Script contains a disabled opcode"
+        );
+    }
+
+    #[test]
+    fn op_count_limit() {
+        assert!(run_script(&[OP_NOP; MAX_OPS_PER_SCRIPT]).is_ok());
+        assert_eq!(
+            error_message(run_script(&[OP_NOP; MAX_OPS_PER_SCRIPT + 1])),
+            "Script exceeds operation limit"
+        );
+        // Pushes are free, unexecuted opcodes still count
+        let mut script = vec![OP_1; 300];
+        script.extend_from_slice(&[OP_NOP; MAX_OPS_PER_SCRIPT]);
+        assert!(run_script(&script).is_ok());
+        let mut script = vec![OP_0, OP_IF];
+        script.extend_from_slice(&[OP_NOP; MAX_OPS_PER_SCRIPT - 1]);
+        script.push(OP_ENDIF);
+        assert_eq!(
+            error_message(run_script(&script)),
+            "Script exceeds operation limit"
+        );
+    }
+
+    #[test]
+    fn stack_size_limit() {
+        assert_eq!(
+            run_script(&[OP_1; MAX_STACK_SIZE]).unwrap().len(),
+            MAX_STACK_SIZE
+        );
+        assert_eq!(
+            error_message(run_script(&[OP_1; MAX_STACK_SIZE + 1])),
+            "Stack size limit exceeded"
+        );
+        // The alt stack counts towards the limit as well
+        let mut script = vec![OP_1; MAX_STACK_SIZE];
+        script.extend_from_slice(&[OP_TOALTSTACK, OP_1, OP_1]);
+        assert_eq!(
+            error_message(run_script(&script)),
+            "Stack size limit exceeded"
+        );
+    }
+
+    #[test]
+    fn num_encoding() {
+        let cases: [(i64, &[u8]); 11] = [
+            (0, &[]),
+            (1, &[0x01]),
+            (-1, &[0x81]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x00]),
+            (-128, &[0x80, 0x80]),
+            (255, &[0xff, 0x00]),
+            (256, &[0x00, 0x01]),
+            (-256, &[0x00, 0x81]),
+            (0x7fff_ffff, &[0xff, 0xff, 0xff, 0x7f]),
+            (-0x7fff_ffff, &[0xff, 0xff, 0xff, 0xff]),
+        ];
+        for (value, encoding) in cases.iter() {
+            assert_eq!(encode_num(*value), encoding.to_vec());
+            assert_eq!(decode_num(encoding).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn num_decoding_edge_cases() {
+        // Non-minimal encodings and negative zero are accepted as in Bitcoin without
+        // the minimal data policy
+        assert_eq!(decode_num(&[0x01, 0x00]).unwrap(), 1);
+        assert_eq!(decode_num(&[0x01, 0x80]).unwrap(), -1);
+        assert_eq!(decode_num(&[0x80]).unwrap(), 0);
+        assert_eq!(decode_num(&[0x00, 0x00, 0x00, 0x00]).unwrap(), 0);
+        assert!(!cast_to_bool(&[0x00, 0x80]));
+        assert!(cast_to_bool(&[0x80, 0x00]));
+        // Operands are limited to 4 bytes, results may take a fifth
+        assert_eq!(
+            error_message(decode_num(&[0x00, 0x00, 0x00, 0x00, 0x01])),
+            "Script number overflow"
+        );
+        assert_eq!(encode_num(0xffff_ffff), vec![0xff, 0xff, 0xff, 0xff, 0x00]);
+        assert_eq!(
+            error_message(run_script(&[
+                0x04, 0xff, 0xff, 0xff, 0x7f, OP_1ADD, OP_1ADD
+            ])),
+            "Script number overflow"
+        );
+    }
+
+    // The redeem script adds the two pushed numbers and checks the sum is 5.
+    fn execute_p2sh(numbers: &[u8], script_hash: &[u8]) -> Result<(), RitCoinErrror<'static>> {
+        let redeem_script = [OP_ADD, OP_5, OP_EQUALVERIFY, OP_1];
+        let script_pubkey = Output::create_p2sh(10, script_hash)
+            .get_script_pubkey()
+            .to_vec();
+        let mut sig_script = vec![];
+        for number in numbers {
+            push_data(&mut sig_script, &encode_num(*number as i64));
+        }
+        push_data(&mut sig_script, &redeem_script);
+        let transaction = spending_transaction(&script_pubkey);
+        execute(
+            &sig_script,
+            &script_pubkey,
+            &TxContext::new(&transaction, 0),
+        )
+    }
+
+    #[test]
+    fn p2sh_runs_the_redeem_script() {
+        let script_hash = hash160(&[OP_ADD, OP_5, OP_EQUALVERIFY, OP_1]);
+        assert!(execute_p2sh(&[2, 3], &script_hash).is_ok());
+        // The script pubkey only checks the hash of the redeem script
+        assert_eq!(
+            error_message(execute_p2sh(&[2, 3], &hash160(&[OP_1]))),
+            "Script evaluated to false"
+        );
+        assert_eq!(
+            error_message(execute_p2sh(&[2, 2], &script_hash)),
+            "Script verification failed"
+        );
+        assert_eq!(
+            error_message(execute_p2sh(&[2], &script_hash)),
+            "Stack has too few elements"
+        );
+    }
+
+    #[test]
+    fn p2sh_signature_script_must_only_push_data() {
+        let redeem_script = [OP_1];
+        let script_pubkey = Output::create_p2sh(10, &hash160(&redeem_script))
+            .get_script_pubkey()
+            .to_vec();
+        let transaction = spending_transaction(&script_pubkey);
+        let context = TxContext::new(&transaction, 0);
+        let mut sig_script = vec![OP_1, OP_DROP];
+        push_data(&mut sig_script, &redeem_script);
+        assert_eq!(
+            error_message(execute(&sig_script, &script_pubkey, &context)),
+            "Signature script must only push data"
+        );
+        assert!(execute(&sig_script[2..], &script_pubkey, &context).is_ok());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::errors::*;
+use crate::opcodes::*;
+use crate::script::{self, Instruction, Instructions};
+
+// Human readable scripts. Opcodes are written by name and pushes as the hex of the
+// pushed data. Anything else, non-minimal pushes, unknown opcodes and a truncated push
+// at the end of a script, is written as its raw bytes prefixed with 0x, so every
+// script can be assembled back to the same bytes.
+
+const OPCODE_NAMES: [(u8, &str); 112] = [
+    (OP_0, "OP_0"),
+    (OP_PUSHDATA1, "OP_PUSHDATA1"),
+    (OP_PUSHDATA2, "OP_PUSHDATA2"),
+    (OP_PUSHDATA4, "OP_PUSHDATA4"),
+    (OP_1NEGATE, "OP_1NEGATE"),
+    (OP_RESERVED, "OP_RESERVED"),
+    (OP_1, "OP_1"),
+    (OP_2, "OP_2"),
+    (OP_3, "OP_3"),
+    (OP_4, "OP_4"),
+    (OP_5, "OP_5"),
+    (OP_6, "OP_6"),
+    (OP_7, "OP_7"),
+    (OP_8, "OP_8"),
+    (OP_9, "OP_9"),
+    (OP_10, "OP_10"),
+    (OP_11, "OP_11"),
+    (OP_12, "OP_12"),
+    (OP_13, "OP_13"),
+    (OP_14, "OP_14"),
+    (OP_15, "OP_15"),
+    (OP_16, "OP_16"),
+    (OP_NOP, "OP_NOP"),
+    (OP_VER, "OP_VER"),
+    (OP_IF, "OP_IF"),
+    (OP_NOTIF, "OP_NOTIF"),
+    (OP_VERIF, "OP_VERIF"),
+    (OP_VERNOTIF, "OP_VERNOTIF"),
+    (OP_ELSE, "OP_ELSE"),
+    (OP_ENDIF, "OP_ENDIF"),
+    (OP_VERIFY, "OP_VERIFY"),
+    (OP_RETURN, "OP_RETURN"),
+    (OP_TOALTSTACK, "OP_TOALTSTACK"),
+    (OP_FROMALTSTACK, "OP_FROMALTSTACK"),
+    (OP_2DROP, "OP_2DROP"),
+    (OP_2DUP, "OP_2DUP"),
+    (OP_3DUP, "OP_3DUP"),
+    (OP_2OVER, "OP_2OVER"),
+    (OP_2ROT, "OP_2ROT"),
+    (OP_2SWAP, "OP_2SWAP"),
+    (OP_IFDUP, "OP_IFDUP"),
+    (OP_DEPTH, "OP_DEPTH"),
+    (OP_DROP, "OP_DROP"),
+    (OP_DUP, "OP_DUP"),
+    (OP_NIP, "OP_NIP"),
+    (OP_OVER, "OP_OVER"),
+    (OP_PICK, "OP_PICK"),
+    (OP_ROLL, "OP_ROLL"),
+    (OP_ROT, "OP_ROT"),
+    (OP_SWAP, "OP_SWAP"),
+    (OP_TUCK, "OP_TUCK"),
+    (OP_CAT, "OP_CAT"),
+    (OP_SUBSTR, "OP_SUBSTR"),
+    (OP_LEFT, "OP_LEFT"),
+    (OP_RIGHT, "OP_RIGHT"),
+    (OP_SIZE, "OP_SIZE"),
+    (OP_INVERT, "OP_INVERT"),
+    (OP_AND, "OP_AND"),
+    (OP_OR, "OP_OR"),
+    (OP_XOR, "OP_XOR"),
+    (OP_EQUAL, "OP_EQUAL"),
+    (OP_EQUALVERIFY, "OP_EQUALVERIFY"),
+    (OP_RESERVED1, "OP_RESERVED1"),
+    (OP_RESERVED2, "OP_RESERVED2"),
+    (OP_1ADD, "OP_1ADD"),
+    (OP_1SUB, "OP_1SUB"),
+    (OP_2MUL, "OP_2MUL"),
+    (OP_2DIV, "OP_2DIV"),
+    (OP_NEGATE, "OP_NEGATE"),
+    (OP_ABS, "OP_ABS"),
+    (OP_NOT, "OP_NOT"),
+    (OP_0NOTEQUAL, "OP_0NOTEQUAL"),
+    (OP_ADD, "OP_ADD"),
+    (OP_SUB, "OP_SUB"),
+    (OP_MUL, "OP_MUL"),
+    (OP_DIV, "OP_DIV"),
+    (OP_MOD, "OP_MOD"),
+    (OP_LSHIFT, "OP_LSHIFT"),
+    (OP_RSHIFT, "OP_RSHIFT"),
+    (OP_BOOLAND, "OP_BOOLAND"),
+    (OP_BOOLOR, "OP_BOOLOR"),
+    (OP_NUMEQUAL, "OP_NUMEQUAL"),
+    (OP_NUMEQUALVERIFY, "OP_NUMEQUALVERIFY"),
+    (OP_NUMNOTEQUAL, "OP_NUMNOTEQUAL"),
+    (OP_LESSTHAN, "OP_LESSTHAN"),
+    (OP_GREATERTHAN, "OP_GREATERTHAN"),
+    (OP_LESSTHANOREQUAL, "OP_LESSTHANOREQUAL"),
+    (OP_GREATERTHANOREQUAL, "OP_GREATERTHANOREQUAL"),
+    (OP_MIN, "OP_MIN"),
+    (OP_MAX, "OP_MAX"),
+    (OP_WITHIN, "OP_WITHIN"),
+    (OP_RIPEMD160, "OP_RIPEMD160"),
+    (OP_SHA1, "OP_SHA1"),
+    (OP_SHA256, "OP_SHA256"),
+    (OP_HASH160, "OP_HASH160"),
+    (OP_HASH256, "OP_HASH256"),
+    (OP_CODESEPARATOR, "OP_CODESEPARATOR"),
+    (OP_CHECKSIG, "OP_CHECKSIG"),
+    (OP_CHECKSIGVERIFY, "OP_CHECKSIGVERIFY"),
+    (OP_CHECKMULTISIG, "OP_CHECKMULTISIG"),
+    (OP_CHECKMULTISIGVERIFY, "OP_CHECKMULTISIGVERIFY"),
+    (OP_NOP1, "OP_NOP1"),
+    (OP_CHECKLOCKTIMEVERIFY, "OP_CHECKLOCKTIMEVERIFY"),
+    (OP_CHECKSEQUENCEVERIFY, "OP_CHECKSEQUENCEVERIFY"),
+    (OP_NOP4, "OP_NOP4"),
+    (OP_NOP5, "OP_NOP5"),
+    (OP_NOP6, "OP_NOP6"),
+    (OP_NOP7, "OP_NOP7"),
+    (OP_NOP8, "OP_NOP8"),
+    (OP_NOP9, "OP_NOP9"),
+    (OP_NOP10, "OP_NOP10"),
+    (OP_INVALIDOPCODE, "OP_INVALIDOPCODE"),
+];
+
+// Other names of opcodes accepted when assembling.
+const OPCODE_ALIASES: [(u8, &str); 4] = [
+    (OP_FALSE, "OP_FALSE"),
+    (OP_TRUE, "OP_TRUE"),
+    (OP_NOP2, "OP_NOP2"),
+    (OP_NOP3, "OP_NOP3"),
+];
+
+pub fn opcode_name(opcode: u8) -> Option<&'static str> {
+    OPCODE_NAMES
+        .iter()
+        .find(|(code, _)| *code == opcode)
+        .map(|(_, name)| *name)
+}
+
+pub fn opcode_from_name(name: &str) -> Option<u8> {
+    OPCODE_NAMES
+        .iter()
+        .chain(OPCODE_ALIASES.iter())
+        .find(|(_, opcode_name)| *opcode_name == name)
+        .map(|(opcode, _)| *opcode)
+}
+
+pub fn to_asm(script: &[u8]) -> String {
+    let mut tokens = vec![];
+    let mut instructions = Instructions::new(script);
+    loop {
+        let start = instructions.position();
+        let instruction = match instructions.next() {
+            Some(instruction) => instruction,
+            None => break,
+        };
+        let raw = &script[start..instructions.position()];
+        let token = match instruction {
+            Ok(Instruction::Push(_)) if raw == [OP_0] => "OP_0".to_string(),
+            Ok(Instruction::Push(data)) => {
+                let mut minimal = vec![];
+                script::push_data(&mut minimal, data);
+                if minimal == raw {
+                    hex::encode(data)
+                } else {
+                    format!("0x{}", hex::encode(raw))
+                }
+            }
+            Ok(Instruction::Op(opcode)) => match opcode_name(opcode) {
+                Some(name) => name.to_string(),
+                None => format!("0x{}", hex::encode(raw)),
+            },
+            Err(_) => format!("0x{}", hex::encode(&script[start..])),
+        };
+        tokens.push(token);
+    }
+    tokens.join(" ")
+}
+
+pub fn from_asm(asm: &str) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+    let mut script = vec![];
+    for token in asm.split_ascii_whitespace() {
+        if let Some(opcode) = opcode_from_name(token) {
+            script.push(opcode);
+        } else if let Some(raw) = token.strip_prefix("0x") {
+            script.extend_from_slice(&hex::decode(raw)?);
+        } else if token.starts_with("OP_") {
+            return Err(RitCoinErrror::from("Unknown opcode in script"));
+        } else {
+            script::push_data(&mut script, &hex::decode(token)?);
+        }
+    }
+    Ok(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::error_message;
+    use crate::transaction::Output;
+
+    fn assert_round_trip(script: &[u8], asm: &str) {
+        assert_eq!(to_asm(script), asm);
+        assert_eq!(from_asm(asm).unwrap(), script);
+    }
+
+    #[test]
+    fn standard_scripts_round_trip() {
+        let p2pkh = Output::create(1, &[0xab; 20]);
+        assert_round_trip(
+            p2pkh.get_script_pubkey(),
+            &format!(
+                "OP_DUP OP_HASH160 {} OP_EQUALVERIFY OP_CHECKSIG",
+                "ab".repeat(20)
+            ),
+        );
+        let p2sh = Output::create_p2sh(1, &[0xcd; 20]);
+        assert_round_trip(
+            p2sh.get_script_pubkey(),
+            &format!("OP_HASH160 {} OP_EQUAL", "cd".repeat(20)),
+        );
+        let public_keys = vec![vec![2; 33], vec![3; 33]];
+        let multisig = Output::create_multisig(1, 1, &public_keys).unwrap();
+        assert_round_trip(
+            multisig.get_script_pubkey(),
+            &format!(
+                "OP_1 {} {} OP_2 OP_CHECKMULTISIG",
+                "02".repeat(33),
+                "03".repeat(33)
+            ),
+        );
+        assert_round_trip(&[OP_0, OP_RETURN, 0x02, 0xbe, 0xef], "OP_0 OP_RETURN beef");
+    }
+
+    #[test]
+    fn irregular_scripts_round_trip_as_raw_bytes() {
+        // A non-minimal push, an unknown opcode and a push running past the script end
+        assert_round_trip(&[OP_PUSHDATA1, 0x01, 0xaa, OP_NOP], "0x4c01aa OP_NOP");
+        assert_round_trip(&[OP_1, 0xba, OP_1], "OP_1 0xba OP_1");
+        assert_round_trip(&[OP_DUP, 0x05, 0xaa, 0xbb], "OP_DUP 0x05aabb");
+        assert_round_trip(&[OP_PUSHDATA2, 0x01], "0x4d01");
+        assert_round_trip(&[], "");
+    }
+
+    #[test]
+    fn aliases_assemble_to_their_opcodes() {
+        assert_eq!(
+            from_asm("OP_FALSE OP_TRUE OP_NOP2 OP_NOP3").unwrap(),
+            vec![OP_0, OP_1, OP_CHECKLOCKTIMEVERIFY, OP_CHECKSEQUENCEVERIFY]
+        );
+    }
+
+    #[test]
+    fn bad_tokens_fail_to_assemble() {
+        assert_eq!(
+            error_message(from_asm("OP_DUP OP_NOTANOPCODE")),
+            "Unknown opcode in script"
+        );
+        for asm in ["zz", "abc", "0xzz", "0x1", "OP_DUP op_dup"].iter() {
+            match from_asm(asm) {
+                Err(RitCoinErrror::HexError(_)) => (),
+                result => panic!("{} assembled to {:?}", asm, result),
+            }
+        }
+    }
+}
@@ -1,11 +1,287 @@
 use crate::errors::*;
-use crate::transaction::*;
 
-pub fn serialize(transaction: &Transaction) -> Result<Vec<u8>, RitCoinErrror<'static>> {
-    Ok(bincode::serialize(transaction)?)
+// Consensus encoding, version 1. The same bytes are hashed and sent between nodes.
+//
+// Integers are little-endian. Counts and script lengths are compact size varints:
+// values below 0xfd take one byte, otherwise a 0xfd, 0xfe or 0xff marker is followed
+// by a u16, u32 or u64, and the shortest form must be used.
+//
+// OutPoint:    tx id (32 bytes), index (u32)
+// Input:       previous output (OutPoint), script length (varint), sig script, sequence (u32)
+// Output:      amount (u64), script length (varint), script pubkey
+// Transaction: version (i32), input count (varint), inputs, output count (varint),
+//              outputs, lock time (u32)
+// Header:      version (i32), previous block hash (32 bytes), merkle root (32 bytes),
+//              timestamp (u64), bits (u32), nonce (u64)
+// Block:       header, transaction count (varint), transactions
+//
+// Transactions and headers start with their version so the format can evolve.
+// Decoding fails on truncated input, non-canonical varints and trailing bytes.
+
+pub const HASH_SIZE: usize = 32;
+
+pub trait Encodable {
+    fn encode(&self, buffer: &mut Vec<u8>);
+}
+
+pub trait Decodable: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, RitCoinErrror<'static>>;
+}
+
+pub fn serialize<T: Encodable>(value: &T) -> Vec<u8> {
+    let mut buffer = vec![];
+    value.encode(&mut buffer);
+    buffer
+}
+
+pub fn deserialize<T: Decodable>(serialized: &[u8]) -> Result<T, RitCoinErrror<'static>> {
+    let mut reader = Reader::new(serialized);
+    let value = T::decode(&mut reader)?;
+    reader.finish()?;
+    Ok(value)
+}
+
+pub fn to_hex<T: Encodable>(value: &T) -> String {
+    hex::encode(serialize(value))
+}
+
+pub fn write_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_i32(buffer: &mut Vec<u8>, value: i32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_var_int(buffer: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfc => buffer.push(value as u8),
+        0xfd..=0xffff => {
+            buffer.push(0xfd);
+            buffer.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buffer.push(0xfe);
+            buffer.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            buffer.push(0xff);
+            buffer.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+pub fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    write_var_int(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
 }
 
-pub fn deserialize(serialized: &[u8]) -> Result<Transaction, RitCoinErrror<'static>> {
-    let deserialized: Transaction = bincode::deserialize(serialized)?;
-    Ok(deserialized)
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn read_exact(&mut self, len: usize) -> Result<&'a [u8], RitCoinErrror<'static>> {
+        if len > self.remaining() {
+            return Err(RitCoinErrror::from("Unexpected end of encoded data"));
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RitCoinErrror<'static>> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_exact(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, RitCoinErrror<'static>> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, RitCoinErrror<'static>> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, RitCoinErrror<'static>> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, RitCoinErrror<'static>> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_var_int(&mut self) -> Result<u64, RitCoinErrror<'static>> {
+        let (value, minimum) = match self.read_u8()? {
+            0xfd => (u16::from_le_bytes(self.read_array()?) as u64, 0xfd),
+            0xfe => (u32::from_le_bytes(self.read_array()?) as u64, 0x1_0000),
+            0xff => (self.read_u64()?, 0x1_0000_0000),
+            value => (value as u64, 0),
+        };
+        if value < minimum {
+            return Err(RitCoinErrror::from("Non-canonical varint in encoded data"));
+        }
+        Ok(value)
+    }
+
+    // Every counted item takes at least one byte, so larger counts cannot be valid and
+    // are rejected before anything is allocated for them.
+    pub fn read_count(&mut self) -> Result<usize, RitCoinErrror<'static>> {
+        let count = self.read_var_int()?;
+        if count > self.remaining() as u64 {
+            return Err(RitCoinErrror::from("Item count exceeds encoded data"));
+        }
+        Ok(count as usize)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+        let len = self.read_count()?;
+        Ok(self.read_exact(len)?.to_vec())
+    }
+
+    pub fn read_hash(&mut self) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+        Ok(self.read_exact(HASH_SIZE)?.to_vec())
+    }
+
+    pub fn finish(&self) -> Result<(), RitCoinErrror<'static>> {
+        if self.remaining() == 0 {
+            Ok(())
+        } else {
+            Err(RitCoinErrror::from("Trailing bytes after encoded data"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::test_utils::{error_message, BITCOIN_RAW_TX};
+    use crate::transaction::Transaction;
+
+    fn raw_tx() -> Vec<u8> {
+        hex::decode(BITCOIN_RAW_TX).unwrap()
+    }
+
+    #[test]
+    fn var_int_round_trip() {
+        let cases = [
+            (0, 1),
+            (0xfc, 1),
+            (0xfd, 3),
+            (0xffff, 3),
+            (0x1_0000, 5),
+            (0xffff_ffff, 5),
+            (0x1_0000_0000, 9),
+            (u64::MAX, 9),
+        ];
+        for (value, length) in cases.iter() {
+            let mut buffer = vec![];
+            write_var_int(&mut buffer, *value);
+            assert_eq!(buffer.len(), *length);
+            let mut reader = Reader::new(&buffer);
+            assert_eq!(reader.read_var_int().unwrap(), *value);
+            assert!(reader.finish().is_ok());
+        }
+    }
+
+    #[test]
+    fn non_canonical_var_ints_are_rejected() {
+        let encodings: [&[u8]; 3] = [
+            &[0xfd, 0xfc, 0x00],
+            &[0xfe, 0xff, 0xff, 0x00, 0x00],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00],
+        ];
+        for encoding in encodings.iter() {
+            assert_eq!(
+                error_message(Reader::new(encoding).read_var_int()),
+                "Non-canonical varint in encoded data"
+            );
+        }
+    }
+
+    #[test]
+    fn transaction_round_trip() {
+        let transaction: Transaction = deserialize(&raw_tx()).unwrap();
+        assert_eq!(to_hex(&transaction), BITCOIN_RAW_TX);
+        assert_eq!(transaction.get_tx_in().len(), 1);
+        assert_eq!(transaction.get_tx_out().len(), 2);
+        assert_eq!(transaction.get_tx_out()[0].get_amount(), 1_000_000_000);
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let block = Block::new(vec![7; HASH_SIZE], vec![raw_tx()], 0x207f_ffff);
+        let serialized = serialize(&block);
+        let decoded: Block = deserialize(&serialized).unwrap();
+        assert_eq!(serialize(&decoded), serialized);
+        assert_eq!(decoded.hash(), block.hash());
+        assert_eq!(decoded.get_transactions(), &[raw_tx()][..]);
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut serialized = raw_tx();
+        serialized.push(0);
+        assert_eq!(
+            error_message(deserialize::<Transaction>(&serialized)),
+            "Trailing bytes after encoded data"
+        );
+        let mut block = serialize(&Block::new(vec![0; HASH_SIZE], vec![raw_tx()], 0x207f_ffff));
+        block.push(0);
+        assert!(deserialize::<Block>(&block).is_err());
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let serialized = raw_tx();
+        for length in 0..serialized.len() {
+            assert!(deserialize::<Transaction>(&serialized[..length]).is_err());
+        }
+        assert_eq!(
+            error_message(deserialize::<Transaction>(
+                &serialized[..serialized.len() - 1]
+            )),
+            "Unexpected end of encoded data"
+        );
+    }
+
+    #[test]
+    fn oversized_counts_are_rejected() {
+        // Input count of u64::MAX right after the version
+        let mut serialized = vec![1, 0, 0, 0];
+        write_var_int(&mut serialized, u64::MAX);
+        assert_eq!(
+            error_message(deserialize::<Transaction>(&serialized)),
+            "Item count exceeds encoded data"
+        );
+        // Script length past the end of the data
+        let mut reader = Reader::new(&[0xfd, 0x00, 0x01, 0xaa]);
+        assert_eq!(
+            error_message(reader.read_bytes()),
+            "Item count exceeds encoded data"
+        );
+        // Transaction count past the end of a block, the header takes 88 bytes
+        let block = Block::new(vec![0; HASH_SIZE], vec![raw_tx()], 0x207f_ffff);
+        let mut serialized = serialize(&block)[..88].to_vec();
+        write_var_int(&mut serialized, 0xffff_ffff);
+        assert_eq!(
+            error_message(deserialize::<Block>(&serialized)),
+            "Item count exceeds encoded data"
+        );
+    }
 }
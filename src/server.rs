@@ -3,21 +3,18 @@ use crate::*;
 use actix_web::{middleware, web, App, HttpServer};
 use std::env;
 
-pub const DEFAULT_NODE_ADDRESS: &str = "http://0.0.0.0:3000";
-pub const DEFAULT_ADDRESS: &str = "http://0.0.0.0:3000";
-pub const DEFAULT_PORT: &str = "3000";
 pub const DEFAULT_IP: &str = "0.0.0.0";
 pub const BROADCAST_RESOURCE: &str = "/transaction/new";
 pub const PENDINGS_RESOURCE: &str = "/transaction/pendings";
 pub const CHAIN_RESOURCE: &str = "/chain";
 pub const NODES_RESOURCE: &str = "/nodes";
 pub const CHAIN_LENGTH_RESOURCE: &str = "/chain/length";
+pub const CHAIN_WORK_RESOURCE: &str = "/chain/work";
 
 pub fn run(ritcoin_state: Arc<RitCoinState>) -> std::io::Result<()> {
     let port = env::var("PORT")
-        .unwrap_or_else(|_| DEFAULT_PORT.to_string())
-        .parse()
-        .expect("PORT must be a number");
+        .map(|port| port.parse().expect("PORT must be a number"))
+        .unwrap_or(ritcoin_state.params.default_port);
     HttpServer::new(move || {
         App::new()
             .data(ritcoin_state.clone())
@@ -29,6 +26,7 @@ pub fn run(ritcoin_state: Arc<RitCoinState>) -> std::io::Result<()> {
             .service(
                 web::resource(CHAIN_LENGTH_RESOURCE).route(web::post().to(handle_chain_length)),
             )
+            .service(web::resource(CHAIN_WORK_RESOURCE).route(web::post().to(handle_chain_work)))
     })
     .bind((DEFAULT_IP, port))?
     .run()
@@ -0,0 +1,57 @@
+use crate::block::Block;
+use crate::chain_params::ChainParams;
+use crate::errors::*;
+use crate::pow::U256;
+use crate::serializer;
+use crate::transaction::Transaction;
+use std::{env, fs, process};
+
+// Transaction f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16 from
+// Bitcoin block 170, the first payment between two people.
+pub const BITCOIN_RAW_TX: &str = "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+pub const BITCOIN_TXID: &str = "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16";
+
+// Message of an error built from a string, to assert which check rejected the input.
+pub fn error_message<T>(result: Result<T, RitCoinErrror<'static>>) -> &'static str {
+    match result {
+        Err(RitCoinErrror::StrError(message)) => message,
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("expected an error"),
+    }
+}
+
+// Regtest parameters with an empty data dir of their own, so tests running in parallel
+// do not share block files, utxo set or mempool. Coinbases mature after one block.
+pub fn test_params(name: &str) -> ChainParams {
+    let data_dir = env::temp_dir().join(format!("ritcoin-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&data_dir);
+    let params = ChainParams {
+        data_dir: Box::leak(data_dir.to_string_lossy().into_owned().into_boxed_str()),
+        coinbase_maturity: 1,
+        ..ChainParams::regtest()
+    };
+    params.create_data_dir().unwrap();
+    params
+}
+
+// Builds the block following previous_blocks and grinds its nonce until the block meets
+// its target, which takes a couple of tries at the regtest limit.
+pub fn mine_block(
+    previous_blocks: &[Block],
+    transactions: &[Transaction],
+    params: &ChainParams,
+) -> Block {
+    let mut block = Block::new(
+        previous_blocks
+            .last()
+            .map(Block::hash)
+            .unwrap_or_else(|| vec![0; serializer::HASH_SIZE]),
+        transactions.iter().map(serializer::serialize).collect(),
+        Block::next_bits(previous_blocks, params),
+    );
+    block.update_timestamp(Block::median_time_past(previous_blocks));
+    while U256::from_be_bytes(&block.hash()) > block.get_target() {
+        block.increment_nonce();
+    }
+    block
+}
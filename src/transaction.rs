@@ -1,20 +1,35 @@
+use crate::block::Block;
+use crate::chain_params::ChainParams;
 use crate::errors::*;
 use crate::hash::*;
 use crate::opcodes::*;
-use crate::script;
+use crate::script::{self, Instruction, Instructions};
+use crate::script_asm;
+use crate::serializer::{self, Decodable, Encodable, Reader};
 use crate::utxo_set::{Utxo, UtxoSet};
 use crate::wallet;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use serde_json::json;
 use std::collections::HashSet;
+use std::fmt;
 
 const VERSION: i32 = 1;
 
-const SIGHASH_ALL: u8 = 1;
-const SIGHASH_NONE: u8 = 2;
-const SIGHASH_SINGLE: u8 = 3;
+pub const SIGHASH_ALL: u8 = 1;
+pub const SIGHASH_NONE: u8 = 2;
+pub const SIGHASH_SINGLE: u8 = 3;
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+// Lock times below the threshold are block heights, the rest are unix timestamps.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+// Relative lock time encoding of an input sequence, as in BIP68.
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct OutPoint {
     tx_id: Vec<u8>,
     index: u32,
@@ -25,15 +40,34 @@ impl OutPoint {
         Self { tx_id, index }
     }
 
-    pub fn get_tx_id(&self) -> &[u8] {
-        &self.tx_id
-    }
-
     pub fn get(&self) -> (&[u8], u32) {
         (&self.tx_id, self.index)
     }
 }
 
+// Written as <tx id>:<index>, the form outpoints are given in on the command line.
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", hex::encode(&self.tx_id), self.index)
+    }
+}
+
+impl Encodable for OutPoint {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.tx_id);
+        serializer::write_u32(buffer, self.index);
+    }
+}
+
+impl Decodable for OutPoint {
+    fn decode(reader: &mut Reader) -> Result<Self, RitCoinErrror<'static>> {
+        Ok(Self {
+            tx_id: reader.read_hash()?,
+            index: reader.read_u32()?,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Input {
     previous_output: OutPoint,
@@ -50,10 +84,17 @@ impl Input {
             previous_output,
             script_bytes: script_pubkey.len() as u16,
             sig_script: script_pubkey.to_vec(),
-            sequence: std::u32::MAX,
+            sequence: SEQUENCE_FINAL,
         }
     }
 
+    // Unsigned inputs spending a P2SH output carry the redeem script as script code.
+    pub fn create_p2sh(utxo: &Utxo, redeem_script: &[u8]) -> Self {
+        let mut input = Self::create(utxo);
+        input.set_sig_script(redeem_script.to_vec());
+        input
+    }
+
     pub fn create_inputs(used_utxos: &[&Utxo]) -> Vec<Self> {
         let mut inputs = vec![];
         used_utxos
@@ -66,25 +107,64 @@ impl Input {
         &self.sig_script
     }
 
-    pub fn get_public_key(&self) -> &[u8] {
-        &self.sig_script[self.sig_script.len() - 65..]
+    // The redeem script of a P2SH input is its whole script while unsigned and the last
+    // push once signed.
+    pub fn get_redeem_script(&self, script_hash: &[u8]) -> Option<Vec<u8>> {
+        if hash160(&self.sig_script) == script_hash {
+            return Some(self.sig_script.clone());
+        }
+        match Instructions::new(&self.sig_script).last()? {
+            Ok(Instruction::Push(redeem_script)) if hash160(redeem_script) == script_hash => {
+                Some(redeem_script.to_vec())
+            }
+            _ => None,
+        }
     }
 
-    pub fn hash(&self, hasher: &mut Sha256, sig_script: bool) {
-        hasher.input(&self.previous_output.tx_id);
-        hasher.input(self.previous_output.index.to_string());
-        hasher.input(self.script_bytes.to_string());
-        if sig_script {
-            hasher.input(&self.sig_script);
-        } else {
-            hasher.input(0.to_string())
-        }
-        hasher.input(self.sequence.to_string());
+    fn set_sig_script(&mut self, sig_script: Vec<u8>) {
+        self.script_bytes = sig_script.len() as u16;
+        self.sig_script = sig_script;
+    }
+
+    fn encode_with_script(&self, buffer: &mut Vec<u8>, sig_script: &[u8]) {
+        self.previous_output.encode(buffer);
+        serializer::write_bytes(buffer, sig_script);
+        serializer::write_u32(buffer, self.sequence);
     }
 
     pub fn get_previous_output(&self) -> &OutPoint {
         &self.previous_output
     }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "previous_output": self.previous_output.to_string(),
+            "sig_script": {
+                "asm": script_asm::to_asm(&self.sig_script),
+                "hex": hex::encode(&self.sig_script),
+            },
+            "sequence": self.sequence,
+        })
+    }
+}
+
+impl Encodable for Input {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.encode_with_script(buffer, &self.sig_script)
+    }
+}
+
+impl Decodable for Input {
+    fn decode(reader: &mut Reader) -> Result<Self, RitCoinErrror<'static>> {
+        let previous_output = OutPoint::decode(reader)?;
+        let sig_script = reader.read_bytes()?;
+        Ok(Self {
+            previous_output,
+            script_bytes: sig_script.len() as u16,
+            sig_script,
+            sequence: reader.read_u32()?,
+        })
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -95,32 +175,109 @@ pub struct Output {
 }
 
 impl Output {
-    fn create(amount: u64, receiver_pkhash: &[u8]) -> Self {
+    pub fn new(amount: u64, script_pubkey: Vec<u8>) -> Self {
+        Self {
+            amount,
+            script_length: script_pubkey.len() as u16,
+            script_pubkey,
+        }
+    }
+
+    pub fn create(amount: u64, receiver_pkhash: &[u8]) -> Self {
         let mut script_pubkey = Vec::new();
         script_pubkey.push(OP_DUP);
         script_pubkey.push(OP_HASH160);
+        script_pubkey.push(receiver_pkhash.len() as u8);
         script_pubkey.extend_from_slice(receiver_pkhash);
         script_pubkey.push(OP_EQUALVERIFY);
         script_pubkey.push(OP_CHECKSIG);
-        Self {
-            amount,
-            script_length: script_pubkey.len() as u16,
-            script_pubkey,
+        Self::new(amount, script_pubkey)
+    }
+
+    pub fn create_p2sh(amount: u64, script_hash: &[u8]) -> Self {
+        let mut script_pubkey = vec![OP_HASH160, script_hash.len() as u8];
+        script_pubkey.extend_from_slice(script_hash);
+        script_pubkey.push(OP_EQUAL);
+        Self::new(amount, script_pubkey)
+    }
+
+    pub fn create_for_address(
+        amount: u64,
+        address: &str,
+        params: &ChainParams,
+    ) -> Result<Self, RitCoinErrror<'static>> {
+        match wallet::decode_address(address, params)? {
+            (prefix, script_hash) if prefix == params.script_address_prefix => {
+                Ok(Self::create_p2sh(amount, &script_hash))
+            }
+            (_, pkhash) => Ok(Self::create(amount, &pkhash)),
         }
     }
 
-    pub fn create_single(
+    // Bare m-of-n multisig: OP_m <public key>... OP_n OP_CHECKMULTISIG.
+    pub fn create_multisig(
         amount: u64,
-        utxo_total: u64,
-        sender_pk_hash: &[u8],
-        receiver_pkhash: &[u8],
-    ) -> Vec<Self> {
-        let mut outputs = vec![];
-        let output = Self::create(amount, receiver_pkhash);
-        outputs.push(output);
-        if utxo_total - amount != 0 {
-            let remainder = Self::create(utxo_total - amount, sender_pk_hash);
-            outputs.push(remainder)
+        required: usize,
+        public_keys: &[Vec<u8>],
+    ) -> Result<Self, RitCoinErrror<'static>> {
+        if required == 0 || required > public_keys.len() || public_keys.len() > 16 {
+            return Err(RitCoinErrror::from(
+                "Multisig needs 1 to 16 public keys and at most as many required signatures",
+            ));
+        }
+        let mut script_pubkey = vec![script::small_int_opcode(required)];
+        for public_key in public_keys {
+            script::push_data(&mut script_pubkey, public_key);
+        }
+        script_pubkey.push(script::small_int_opcode(public_keys.len()));
+        script_pubkey.push(OP_CHECKMULTISIG);
+        Ok(Self::new(amount, script_pubkey))
+    }
+
+    // The recipient claims with the secret, the sender can take the coins back once the
+    // lock time is reached:
+    // OP_IF OP_SIZE <32> OP_EQUALVERIFY OP_SHA256 <secret hash> OP_EQUALVERIFY
+    //     OP_DUP OP_HASH160 <recipient pubkey hash>
+    // OP_ELSE <lock time> OP_CHECKLOCKTIMEVERIFY OP_DROP
+    //     OP_DUP OP_HASH160 <refund pubkey hash>
+    // OP_ENDIF OP_EQUALVERIFY OP_CHECKSIG
+    pub fn create_htlc(
+        amount: u64,
+        secret_hash: &[u8],
+        recipient_pkhash: &[u8],
+        lock_time: u32,
+        refund_pkhash: &[u8],
+    ) -> Self {
+        let mut script_pubkey = vec![OP_IF, OP_SIZE];
+        script::push_data(
+            &mut script_pubkey,
+            &script::encode_num(script::HTLC_SECRET_SIZE as i64),
+        );
+        script_pubkey.extend_from_slice(&[OP_EQUALVERIFY, OP_SHA256]);
+        script::push_data(&mut script_pubkey, secret_hash);
+        script_pubkey.extend_from_slice(&[OP_EQUALVERIFY, OP_DUP, OP_HASH160]);
+        script::push_data(&mut script_pubkey, recipient_pkhash);
+        script_pubkey.push(OP_ELSE);
+        script::push_data(&mut script_pubkey, &script::encode_num(lock_time as i64));
+        script_pubkey.extend_from_slice(&[OP_CHECKLOCKTIMEVERIFY, OP_DROP, OP_DUP, OP_HASH160]);
+        script::push_data(&mut script_pubkey, refund_pkhash);
+        script_pubkey.extend_from_slice(&[OP_ENDIF, OP_EQUALVERIFY, OP_CHECKSIG]);
+        Self::new(amount, script_pubkey)
+    }
+
+    // Carries data instead of paying anyone: OP_RETURN <data>
+    pub fn create_data(data: &[u8]) -> Self {
+        let mut script_pubkey = vec![OP_RETURN];
+        script::push_data(&mut script_pubkey, data);
+        Self::new(0, script_pubkey)
+    }
+
+    // Pays the payment output and returns the rest of utxo_total to the sender.
+    pub fn create_single(payment: Self, utxo_total: u64, sender_pk_hash: &[u8]) -> Vec<Self> {
+        let remainder = utxo_total - payment.amount;
+        let mut outputs = vec![payment];
+        if remainder != 0 {
+            outputs.push(Self::create(remainder, sender_pk_hash))
         }
         outputs
     }
@@ -129,16 +286,100 @@ impl Output {
         &self.script_pubkey
     }
 
+    pub fn get_pkhash(&self) -> Option<&[u8]> {
+        match self.script_pubkey.as_slice() {
+            [OP_DUP, OP_HASH160, 0x14, pkhash @ .., OP_EQUALVERIFY, OP_CHECKSIG]
+                if pkhash.len() == 20 =>
+            {
+                Some(pkhash)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_script_hash(&self) -> Option<&[u8]> {
+        match self.script_pubkey.as_slice() {
+            [OP_HASH160, 0x14, script_hash @ .., OP_EQUAL] if script_hash.len() == 20 => {
+                Some(script_hash)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_multisig(&self) -> Option<(usize, Vec<&[u8]>)> {
+        script::get_multisig(&self.script_pubkey)
+    }
+
+    pub fn get_data(&self) -> Option<&[u8]> {
+        script::get_data(&self.script_pubkey)
+    }
+
+    pub fn is_unspendable(&self) -> bool {
+        script::is_unspendable(&self.script_pubkey)
+    }
+
     pub fn get_amount(&self) -> u64 {
         self.amount
     }
 
-    pub fn hash(&self, hasher: &mut Sha256) {
-        hasher.input(self.amount.to_string());
-        hasher.input(self.script_length.to_string());
-        hasher.input(&self.script_pubkey);
+    pub fn to_json(&self, params: &ChainParams) -> serde_json::Value {
+        let (script_type, address) = if let Some(pkhash) = self.get_pkhash() {
+            ("pubkeyhash", wallet::pkhash_to_address(pkhash, params).ok())
+        } else if let Some(script_hash) = self.get_script_hash() {
+            (
+                "scripthash",
+                wallet::script_hash_to_address(script_hash, params).ok(),
+            )
+        } else if self.get_multisig().is_some() {
+            ("multisig", None)
+        } else if script::get_htlc(&self.script_pubkey).is_some() {
+            ("htlc", None)
+        } else if self.get_data().is_some() {
+            ("nulldata", None)
+        } else {
+            ("nonstandard", None)
+        };
+        json!({
+            "amount": self.amount,
+            "script_pubkey": {
+                "asm": script_asm::to_asm(&self.script_pubkey),
+                "hex": hex::encode(&self.script_pubkey),
+                "type": script_type,
+                "address": address,
+            },
+        })
+    }
+
+    // Outputs before the signed one under SIGHASH_SINGLE are committed to as empty
+    // outputs with the maximum amount.
+    fn null() -> Self {
+        Self {
+            amount: u64::MAX,
+            script_length: 0,
+            script_pubkey: vec![],
+        }
     }
 }
+
+impl Encodable for Output {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        serializer::write_u64(buffer, self.amount);
+        serializer::write_bytes(buffer, &self.script_pubkey);
+    }
+}
+
+impl Decodable for Output {
+    fn decode(reader: &mut Reader) -> Result<Self, RitCoinErrror<'static>> {
+        let amount = reader.read_u64()?;
+        let script_pubkey = reader.read_bytes()?;
+        Ok(Self {
+            amount,
+            script_length: script_pubkey.len() as u16,
+            script_pubkey,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     version: i32,
@@ -149,24 +390,35 @@ pub struct Transaction {
     lock_time: u32,
 }
 
+// Amounts come from transactions anyone can craft, so sums fail instead of wrapping.
+pub fn sum_amounts(amounts: impl IntoIterator<Item = u64>) -> Result<u64, RitCoinErrror<'static>> {
+    let mut sum: u64 = 0;
+    for amount in amounts {
+        sum = sum
+            .checked_add(amount)
+            .ok_or_else(|| RitCoinErrror::from("Sum of amounts overflows"))?;
+    }
+    Ok(sum)
+}
+
 pub trait CoinBaseTransaction {
     fn new(receiver_pkhash: &[u8], block_height: u32, coinbase_amount: u64) -> Self;
 }
 
 impl CoinBaseTransaction for Transaction {
     fn new(receiver_pkhash: &[u8], block_height: u32, coinbase_amount: u64) -> Self {
-        let previous_output = OutPoint::new(vec![0; 64], std::u32::MAX);
-        let sig_script: Vec<_> = block_height
-            .to_le_bytes()
-            .into_iter()
-            .filter(|i| **i != 0)
-            .map(|i| i.to_owned())
-            .collect();
+        let previous_output = OutPoint::new(vec![0; serializer::HASH_SIZE], u32::MAX);
+        let mut height_bytes = block_height.to_le_bytes().to_vec();
+        while height_bytes.last() == Some(&0) {
+            height_bytes.pop();
+        }
+        let mut sig_script = vec![height_bytes.len() as u8];
+        sig_script.extend_from_slice(&height_bytes);
         let tx_in = vec![Input {
             previous_output,
             script_bytes: sig_script.len() as u16,
             sig_script,
-            sequence: std::u32::MAX,
+            sequence: SEQUENCE_FINAL,
         }];
         let tx_out = vec![Output::create(coinbase_amount, receiver_pkhash)];
         Self {
@@ -192,98 +444,294 @@ impl Transaction {
         }
     }
 
+    // Id referenced by outpoints. Signature scripts are left out so re-encoding a
+    // signature cannot change it, except for the coinbase whose script carries the
+    // block height and keeps coinbase ids unique.
+    pub fn tx_id(&self) -> Vec<u8> {
+        self.hash_with_scripts(self.is_coinbase())
+    }
+
+    // Hash of the whole transaction including signatures, tells relayed copies apart.
     pub fn hash(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.input(&self.version.to_string());
-        hasher.input(&self.tx_in_count.to_string());
-        for tx_in in &self.tx_in {
-            tx_in.hash(&mut hasher, true)
-        }
-        for tx_out in &self.tx_out {
-            tx_out.hash(&mut hasher)
+        self.hash_with_scripts(true)
+    }
+
+    // The consensus encoding matches Bitcoin's legacy raw transaction format, so raw
+    // hex can be exchanged with standard tooling. Witness serialized transactions
+    // start with a zero marker and a non-zero flag where the input count would be.
+    pub fn to_raw_hex(&self) -> String {
+        serializer::to_hex(self)
+    }
+
+    pub fn from_raw_hex(raw: &str) -> Result<Self, RitCoinErrror<'static>> {
+        let raw = hex::decode(raw.trim())?;
+        if raw.len() > 5 && raw[4] == 0 && raw[5] != 0 {
+            return Err(RitCoinErrror::from(
+                "Segregated witness transactions are not supported",
+            ));
         }
-        let sha_256_hash = hasher.result().to_vec();
-        sha256(&sha_256_hash)
+        serializer::deserialize(&raw)
+    }
+
+    // Readable view of the transaction with its scripts disassembled.
+    pub fn to_json(&self, params: &ChainParams) -> serde_json::Value {
+        json!({
+            "tx_id": hex::encode(self.tx_id()),
+            "version": self.version,
+            "inputs": self.tx_in.iter().map(Input::to_json).collect::<Vec<_>>(),
+            "outputs": self
+                .tx_out
+                .iter()
+                .map(|output| output.to_json(params))
+                .collect::<Vec<_>>(),
+            "lock_time": self.lock_time,
+        })
     }
 
-    pub fn hash_one(&self, input: &Input) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.input(&self.version.to_string());
-        hasher.input(&self.tx_in_count.to_string());
+    // Txid as shown by Bitcoin tooling, the byte reversed hex of the full hash.
+    pub fn bitcoin_txid(&self) -> String {
+        let mut hash = self.hash();
+        hash.reverse();
+        hex::encode(hash)
+    }
+
+    fn hash_with_scripts(&self, sig_scripts: bool) -> Vec<u8> {
+        let mut buffer = vec![];
+        self.encode_with_scripts(&mut buffer, sig_scripts);
+        hash256(&buffer)
+    }
+
+    fn encode_with_scripts(&self, buffer: &mut Vec<u8>, sig_scripts: bool) {
+        serializer::write_i32(buffer, self.version);
+        serializer::write_var_int(buffer, self.tx_in.len() as u64);
         for tx_in in &self.tx_in {
-            if *input != *tx_in {
-                tx_in.hash(&mut hasher, false)
+            if sig_scripts {
+                tx_in.encode(buffer)
             } else {
-                tx_in.hash(&mut hasher, true)
+                tx_in.encode_with_script(buffer, &[])
             }
         }
+        serializer::write_var_int(buffer, self.tx_out.len() as u64);
         for tx_out in &self.tx_out {
-            tx_out.hash(&mut hasher)
+            tx_out.encode(buffer)
         }
-        hasher.input(SIGHASH_ALL.to_string());
-        let sha_256_hash = hasher.result().to_vec();
-        sha256(&sha_256_hash)
+        serializer::write_u32(buffer, self.lock_time);
     }
 
-    pub fn hash_all(&self) -> Vec<Vec<u8>> {
-        self.tx_in
-            .iter()
-            .map(|input| self.hash_one(input))
-            .collect()
+    // Hash signed by the input at input_index. script_code stands in for that input's
+    // signature script while every other input is committed to without its script.
+    // SIGHASH_NONE commits to no outputs and SIGHASH_SINGLE only to the output with
+    // the same index, both leave the sequences of other inputs open, and
+    // SIGHASH_ANYONECANPAY commits to the signed input alone.
+    pub fn signature_hash(
+        &self,
+        input_index: usize,
+        script_code: &[u8],
+        sighash_type: u8,
+    ) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+        let base_type = sighash_type & !SIGHASH_ANYONECANPAY;
+        let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+        if input_index >= self.tx_in.len() {
+            return Err(RitCoinErrror::from("Signed input index is out of range"));
+        }
+        if base_type == SIGHASH_SINGLE && input_index >= self.tx_out.len() {
+            return Err(RitCoinErrror::from(
+                "SIGHASH_SINGLE input has no output with the same index",
+            ));
+        }
+        let mut transaction = self.clone();
+        for (i, tx_in) in transaction.tx_in.iter_mut().enumerate() {
+            if i == input_index {
+                tx_in.set_sig_script(script_code.to_vec());
+            } else {
+                tx_in.set_sig_script(vec![]);
+                if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+                    tx_in.sequence = 0;
+                }
+            }
+        }
+        match base_type {
+            SIGHASH_NONE => transaction.tx_out.clear(),
+            SIGHASH_SINGLE => {
+                transaction.tx_out.truncate(input_index + 1);
+                for tx_out in &mut transaction.tx_out[..input_index] {
+                    *tx_out = Output::null();
+                }
+            }
+            _ => (),
+        }
+        if anyone_can_pay {
+            transaction.tx_in = vec![transaction.tx_in.swap_remove(input_index)];
+        }
+        transaction.tx_in_count = transaction.tx_in.len() as u16;
+        transaction.tx_out_count = transaction.tx_out.len() as u16;
+        let mut buffer = serializer::serialize(&transaction);
+        serializer::write_u32(&mut buffer, sighash_type as u32);
+        Ok(hash256(&buffer))
     }
 
-    pub fn calculate_sig_script(signature: &[u8], pub_key: &[u8]) -> Vec<u8> {
+    pub fn calculate_sig_script(signature: &[u8], sighash_type: u8, pub_key: &[u8]) -> Vec<u8> {
         let mut sig_script = vec![];
         sig_script.push((signature.len() + 1) as u8);
         sig_script.extend_from_slice(signature);
-        sig_script.push(1);
+        sig_script.push(sighash_type);
         sig_script.push(pub_key.len() as u8);
         sig_script.extend_from_slice(pub_key);
         sig_script
     }
 
-    pub fn sign(&mut self, private_key: &[u8]) -> Result<(), RitCoinErrror<'static>> {
-        let hashes = self.hash_all();
-        for (i, hash) in hashes.iter().enumerate() {
-            let (signature, pub_key) = wallet::sign(hash, private_key)?;
-            self.tx_in[i].sig_script = Self::calculate_sig_script(&signature, &pub_key);
-            self.tx_in[i].script_bytes = self.tx_in[i].sig_script.len() as u16;
+    // Unsigned inputs carry the script pubkey of the output they spend in their
+    // signature script, it is used as the script code of the signature hash.
+    pub fn sign_input(
+        &mut self,
+        input_index: usize,
+        private_key: &[u8],
+        sighash_type: u8,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let script_code = self.tx_in[input_index].sig_script.clone();
+        let hash = self.signature_hash(input_index, &script_code, sighash_type)?;
+        let (signature, pub_key) = wallet::sign(&hash, private_key)?;
+        self.tx_in[input_index].set_sig_script(Self::calculate_sig_script(
+            &signature,
+            sighash_type,
+            &pub_key,
+        ));
+        Ok(())
+    }
+
+    // Adds this key's signature to a multisig input. Signatures already in the signature
+    // script are kept, ordered like their keys, so every co-signer can sign in turn.
+    // script_code is the multisig script, with p2sh it is the redeem script and is
+    // pushed after the signatures.
+    pub fn sign_multisig_input(
+        &mut self,
+        input_index: usize,
+        private_key: &[u8],
+        script_code: &[u8],
+        p2sh: bool,
+        sighash_type: u8,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let (required, public_keys) = match script::get_multisig(script_code) {
+            Some(multisig) => multisig,
+            None => return Err(RitCoinErrror::from("Output is not a multisig output")),
+        };
+        let public_key = wallet::private_key_to_public_key(private_key)?;
+        let key_index = match public_keys.iter().position(|key| *key == &public_key[..]) {
+            Some(key_index) => key_index,
+            None => {
+                return Err(RitCoinErrror::from(
+                    "Key is not part of the multisig output",
+                ))
+            }
+        };
+        let mut signatures = vec![];
+        let sig_script = self.tx_in[input_index].sig_script.clone();
+        // Unsigned inputs still carry the script code instead of signatures.
+        if sig_script != script_code {
+            for instruction in Instructions::new(&sig_script).skip(1) {
+                if let Instruction::Push(signature) = instruction? {
+                    if let Some((sighash_type, der)) = signature.split_last() {
+                        let hash = self.signature_hash(input_index, script_code, *sighash_type)?;
+                        if let Some(index) = public_keys
+                            .iter()
+                            .position(|key| wallet::verify(&hash, der, key).is_ok())
+                        {
+                            signatures.push((index, signature.to_vec()));
+                        }
+                    }
+                }
+            }
         }
+        if signatures.iter().any(|(index, _)| *index == key_index) {
+            return Err(RitCoinErrror::from("Input is already signed with this key"));
+        }
+        if signatures.len() >= required {
+            return Err(RitCoinErrror::from("Input already has enough signatures"));
+        }
+        let hash = self.signature_hash(input_index, script_code, sighash_type)?;
+        let (mut signature, _) = wallet::sign(&hash, private_key)?;
+        signature.push(sighash_type);
+        signatures.push((key_index, signature));
+        signatures.sort_by_key(|(index, _)| *index);
+        let mut sig_script = vec![OP_0];
+        for (_, signature) in signatures {
+            script::push_data(&mut sig_script, &signature);
+        }
+        if p2sh {
+            script::push_data(&mut sig_script, script_code);
+        }
+        self.tx_in[input_index].set_sig_script(sig_script);
         Ok(())
     }
 
-    pub fn get_original_hashes(&self, utxos: &[&Utxo]) -> Vec<Vec<u8>> {
-        let mut transaction = self.clone();
-        for input in &mut transaction.tx_in {
-            if let Some((script_pubkey, _)) = UtxoSet::get_validation_data(
-                utxos,
-                &input.previous_output.tx_id,
-                input.previous_output.index,
-            ) {
-                input.script_bytes = script_pubkey.len() as u16;
-                input.sig_script = script_pubkey.to_vec();
+    // Signs an input spending a P2SH HTLC output, the unsigned input carries the redeem
+    // script. With the secret the claim branch is taken, without it the refund branch,
+    // which needs the transaction lock time set to the HTLC's.
+    pub fn sign_htlc_input(
+        &mut self,
+        input_index: usize,
+        private_key: &[u8],
+        secret: Option<&[u8]>,
+        sighash_type: u8,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let redeem_script = self.tx_in[input_index].sig_script.clone();
+        let hash = self.signature_hash(input_index, &redeem_script, sighash_type)?;
+        let (signature, pub_key) = wallet::sign(&hash, private_key)?;
+        let mut sig_script = Self::calculate_sig_script(&signature, sighash_type, &pub_key);
+        match secret {
+            Some(secret) => {
+                script::push_data(&mut sig_script, secret);
+                sig_script.push(OP_1);
             }
+            None => sig_script.push(OP_0),
         }
-        transaction.hash_all()
+        script::push_data(&mut sig_script, &redeem_script);
+        self.tx_in[input_index].set_sig_script(sig_script);
+        Ok(())
+    }
+
+    pub fn sign(
+        &mut self,
+        private_key: &[u8],
+        sighash_type: u8,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        for i in 0..self.tx_in.len() {
+            self.sign_input(i, private_key, sighash_type)?;
+        }
+        Ok(())
     }
 
-    pub fn validate(&self, utxos: &[&Utxo]) -> Result<(), RitCoinErrror<'static>> {
-        let mut inputs_sum = 0;
-        let hashes = self.get_original_hashes(utxos);
-        for (i, input) in self.tx_in.iter().enumerate() {
-            if let Some((script_pubkey, amount)) = UtxoSet::get_validation_data(
-                utxos,
-                &input.previous_output.tx_id,
-                input.previous_output.index,
-            ) {
-                inputs_sum += amount;
-                script::execute(input.get_sig_script(), script_pubkey, &hashes[i])?;
+    // Looks up the spent output of every input, failing with all outpoints missing from
+    // the utxo set.
+    pub fn resolve_inputs<'a>(
+        &self,
+        utxo_set: &'a UtxoSet,
+    ) -> Result<Vec<(&'a [u8], u64)>, RitCoinErrror<'static>> {
+        let mut resolved = Vec::with_capacity(self.tx_in.len());
+        let mut missing = vec![];
+        for input in &self.tx_in {
+            match utxo_set.get_validation_data(&input.previous_output) {
+                Some(validation_data) => resolved.push(validation_data),
+                None => missing.push(input.previous_output.clone()),
             }
         }
-        let outputs_sum = self
-            .tx_out
-            .iter()
-            .fold(0_u64, |acc, output| acc + output.amount);
+        if missing.is_empty() {
+            Ok(resolved)
+        } else {
+            Err(RitCoinErrror::MissingInputs(missing))
+        }
+    }
+
+    pub fn validate(&self, utxo_set: &UtxoSet) -> Result<(), RitCoinErrror<'static>> {
+        let mut inputs_sum: u64 = 0;
+        let resolved_inputs = self.resolve_inputs(utxo_set)?;
+        for (i, (script_pubkey, amount)) in resolved_inputs.into_iter().enumerate() {
+            inputs_sum = inputs_sum
+                .checked_add(amount)
+                .ok_or_else(|| RitCoinErrror::from("Sum of inputs overflows"))?;
+            self.execute_input(i, script_pubkey)?;
+        }
+        let outputs_sum = self.get_outputs_sum()?;
         if inputs_sum >= outputs_sum {
             Ok(())
         } else {
@@ -293,26 +741,237 @@ impl Transaction {
         }
     }
 
-    pub fn verify(&self, utxos: &[&Utxo]) -> Result<(), RitCoinErrror<'static>> {
-        let hashes = self.get_original_hashes(utxos);
-        for (i, input) in self.tx_in.iter().enumerate() {
-            if let Some((script_pubkey, _)) = UtxoSet::get_validation_data(
-                utxos,
-                &input.previous_output.tx_id,
-                input.previous_output.index,
-            ) {
-                script::execute(input.get_sig_script(), script_pubkey, &hashes[i])?;
+    pub fn verify(&self, utxo_set: &UtxoSet) -> Result<(), RitCoinErrror<'static>> {
+        let resolved_inputs = self.resolve_inputs(utxo_set)?;
+        for (i, (script_pubkey, _)) in resolved_inputs.into_iter().enumerate() {
+            self.execute_input(i, script_pubkey)?;
+        }
+        Ok(())
+    }
+
+    fn execute_input(
+        &self,
+        input_index: usize,
+        script_pubkey: &[u8],
+    ) -> Result<(), RitCoinErrror<'static>> {
+        script::execute(
+            self.tx_in[input_index].get_sig_script(),
+            script_pubkey,
+            &script::TxContext::new(self, input_index),
+        )
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        match self.tx_in.as_slice() {
+            [input] => {
+                input.previous_output.index == u32::MAX
+                    && input.previous_output.tx_id.iter().all(|byte| *byte == 0)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get_coinbase_height(&self) -> Option<u32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        match self.tx_in[0].sig_script.split_first() {
+            Some((len, height_bytes)) if *len as usize == height_bytes.len() && *len <= 4 => {
+                let mut bytes = [0; 4];
+                bytes[..height_bytes.len()].copy_from_slice(height_bytes);
+                Some(u32::from_le_bytes(bytes))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn check_duplicate_inputs(&self) -> Result<(), RitCoinErrror<'static>> {
+        let mut previous_outputs = HashSet::new();
+        if self
+            .tx_in
+            .iter()
+            .all(|input| previous_outputs.insert(&input.previous_output))
+        {
+            Ok(())
+        } else {
+            Err(RitCoinErrror::from(
+                "Transaction spends the same output more than once",
+            ))
+        }
+    }
+
+    // Relay policy for OP_RETURN outputs: one per transaction, with a single push of at
+    // most max_size bytes.
+    pub fn check_data_outputs(&self, max_size: usize) -> Result<(), RitCoinErrror<'static>> {
+        let mut data_outputs = 0;
+        for output in self.tx_out.iter().filter(|output| output.is_unspendable()) {
+            match output.get_data() {
+                Some(data) if data.len() <= max_size => data_outputs += 1,
+                Some(_) => {
+                    return Err(RitCoinErrror::from(
+                        "Data output exceeds the maximum payload size",
+                    ))
+                }
+                None => {
+                    return Err(RitCoinErrror::from(
+                        "Unspendable output is not a data output",
+                    ))
+                }
+            }
+        }
+        if data_outputs > 1 {
+            return Err(RitCoinErrror::from(
+                "Transaction has more than one data output",
+            ));
+        }
+        Ok(())
+    }
+
+    // Inputs with a final sequence opt out of the lock time, so a non-zero lock time
+    // makes every input non-final for it to take effect.
+    pub fn set_lock_time(&mut self, lock_time: u32) {
+        self.lock_time = lock_time;
+        if lock_time != 0 {
+            for input in &mut self.tx_in {
+                if input.sequence == SEQUENCE_FINAL {
+                    input.sequence = SEQUENCE_FINAL - 1;
+                }
+            }
+        }
+    }
+
+    pub fn is_final(&self, block_height: u32, block_time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let lock_time_reached = if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time < block_height
+        } else {
+            (self.lock_time as u64) < block_time
+        };
+        lock_time_reached
+            || self
+                .tx_in
+                .iter()
+                .all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    // OP_CHECKLOCKTIMEVERIFY: the transaction lock time has to be of the same kind as
+    // the script's, heights or timestamps, at least as large, and enforced by a
+    // non-final input sequence.
+    pub fn check_lock_time(&self, input_index: usize, lock_time: u32) -> bool {
+        (self.lock_time < LOCKTIME_THRESHOLD) == (lock_time < LOCKTIME_THRESHOLD)
+            && lock_time <= self.lock_time
+            && self.tx_in[input_index].sequence != SEQUENCE_FINAL
+    }
+
+    // OP_CHECKSEQUENCEVERIFY: a script sequence with the disable flag always passes,
+    // otherwise the input's relative lock time has to be enabled, of the same kind and
+    // at least as large. Relative lock times apply to every transaction version here.
+    pub fn check_sequence(&self, input_index: usize, sequence: u32) -> bool {
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return true;
+        }
+        let input_sequence = self.tx_in[input_index].sequence;
+        if input_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return false;
+        }
+        let mask = SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK;
+        let (sequence, input_sequence) = (sequence & mask, input_sequence & mask);
+        (sequence & SEQUENCE_LOCKTIME_TYPE_FLAG) == (input_sequence & SEQUENCE_LOCKTIME_TYPE_FLAG)
+            && sequence <= input_sequence
+    }
+
+    // Checks the absolute lock time and the relative lock times of the inputs for
+    // inclusion in the block following previous_blocks. Times are compared against
+    // median time past, relative time locks count from the median time past of the
+    // block before the one that created the spent output.
+    pub fn check_locks(
+        &self,
+        utxo_set: &UtxoSet,
+        previous_blocks: &[Block],
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let spend_height = previous_blocks.len() as u32;
+        let median_time_past = Block::median_time_past(previous_blocks);
+        if !self.is_final(spend_height, median_time_past) {
+            return Err(RitCoinErrror::from(
+                "Transaction is not final, its lock time is not reached",
+            ));
+        }
+        if self.is_coinbase() {
+            return Ok(());
+        }
+        for input in &self.tx_in {
+            if input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let utxo = match utxo_set.get(&input.previous_output) {
+                Some(utxo) => utxo,
+                None => continue,
+            };
+            let lock_value = input.sequence & SEQUENCE_LOCKTIME_MASK;
+            let is_locked = if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let utxo_height = (utxo.get_height() as usize).min(previous_blocks.len());
+                let utxo_time = Block::median_time_past(&previous_blocks[..utxo_height]);
+                median_time_past
+                    < utxo_time + ((lock_value as u64) << SEQUENCE_LOCKTIME_GRANULARITY)
+            } else {
+                spend_height < utxo.get_height() + lock_value
+            };
+            if is_locked {
+                return Err(RitCoinErrror::from(
+                    "Transaction input is locked by its relative lock time",
+                ));
             }
         }
         Ok(())
     }
 
-    pub fn get_pub_keys_from_inputs(&self) -> HashSet<Vec<u8>> {
-        let mut pub_key_set = HashSet::new();
+    pub fn check_coinbase_maturity(
+        &self,
+        utxo_set: &UtxoSet,
+        spend_height: u32,
+        coinbase_maturity: u32,
+    ) -> Result<(), RitCoinErrror<'static>> {
         for input in &self.tx_in {
-            pub_key_set.insert(input.get_public_key().to_vec());
+            if let Some(utxo) = utxo_set.get(&input.previous_output) {
+                if !utxo.is_mature(spend_height, coinbase_maturity) {
+                    return Err(RitCoinErrror::from(
+                        "Transaction spends immature coinbase output",
+                    ));
+                }
+            }
         }
-        pub_key_set
+        Ok(())
+    }
+
+    pub fn get_inputs_sum(&self, utxo_set: &UtxoSet) -> Result<u64, RitCoinErrror<'static>> {
+        sum_amounts(
+            self.tx_in
+                .iter()
+                .filter_map(|input| utxo_set.get_validation_data(&input.previous_output))
+                .map(|(_, amount)| amount),
+        )
+    }
+
+    pub fn get_fee(&self, utxo_set: &UtxoSet) -> Result<u64, RitCoinErrror<'static>> {
+        Ok(self
+            .get_inputs_sum(utxo_set)?
+            .saturating_sub(self.get_outputs_sum()?))
+    }
+
+    pub fn get_outputs_sum(&self) -> Result<u64, RitCoinErrror<'static>> {
+        sum_amounts(self.tx_out.iter().map(|output| output.amount))
+    }
+
+    // No output, nor all of them together, may pay more than can ever exist.
+    pub fn check_output_amounts(&self, max_money: u64) -> Result<(), RitCoinErrror<'static>> {
+        if self.get_outputs_sum()? > max_money {
+            return Err(RitCoinErrror::from(
+                "Transaction outputs exceed the money supply",
+            ));
+        }
+        Ok(())
     }
 
     pub fn get_tx_in(&self) -> &[Input] {
@@ -323,3 +982,724 @@ impl Transaction {
         &self.tx_out
     }
 }
+
+impl Encodable for Transaction {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.encode_with_scripts(buffer, true)
+    }
+}
+
+impl Decodable for Transaction {
+    fn decode(reader: &mut Reader) -> Result<Self, RitCoinErrror<'static>> {
+        let version = reader.read_i32()?;
+        let tx_in_count = reader.read_count()?;
+        let mut tx_in = Vec::with_capacity(tx_in_count);
+        for _ in 0..tx_in_count {
+            tx_in.push(Input::decode(reader)?);
+        }
+        let tx_out_count = reader.read_count()?;
+        let mut tx_out = Vec::with_capacity(tx_out_count);
+        for _ in 0..tx_out_count {
+            tx_out.push(Output::decode(reader)?);
+        }
+        Ok(Self {
+            version,
+            tx_in_count: tx_in.len() as u16,
+            tx_in,
+            tx_out_count: tx_out.len() as u16,
+            tx_out,
+            lock_time: reader.read_u32()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::TxContext;
+    use crate::test_utils::{error_message, BITCOIN_RAW_TX, BITCOIN_TXID};
+
+    const PRIVATE_KEYS: [[u8; 32]; 3] = [[1; 32], [2; 32], [3; 32]];
+
+    fn utxo(key_index: usize) -> Utxo {
+        let public_key = wallet::private_key_to_public_key(&PRIVATE_KEYS[key_index]).unwrap();
+        let output = Output::create(10, &wallet::pk_hash_from_public_key(&public_key));
+        Utxo::new(
+            vec![key_index as u8; serializer::HASH_SIZE],
+            0,
+            output,
+            1,
+            false,
+        )
+    }
+
+    // Spends the outputs of the first two keys to two outputs.
+    fn unsigned_transaction() -> Transaction {
+        Transaction::new(
+            Input::create_inputs(&[&utxo(0), &utxo(1)]),
+            vec![Output::create(8, &[5; 20]), Output::create(9, &[6; 20])],
+        )
+    }
+
+    fn signed_transaction(input_index: usize, sighash_type: u8) -> Transaction {
+        let mut transaction = unsigned_transaction();
+        transaction
+            .sign_input(input_index, &PRIVATE_KEYS[input_index], sighash_type)
+            .unwrap();
+        transaction
+    }
+
+    // Spends an output of the given kind to a single pay to pubkey hash output.
+    fn spending_transaction(output: Output) -> Transaction {
+        let utxo = Utxo::new(vec![9; serializer::HASH_SIZE], 0, output, 1, false);
+        Transaction::new(
+            vec![Input::create(&utxo)],
+            vec![Output::create(9, &[5; 20])],
+        )
+    }
+
+    fn execute_input(
+        transaction: &Transaction,
+        input_index: usize,
+        script_pubkey: &[u8],
+    ) -> Result<(), RitCoinErrror<'static>> {
+        script::execute(
+            &transaction.tx_in[input_index].sig_script,
+            script_pubkey,
+            &TxContext::new(transaction, input_index),
+        )
+    }
+
+    fn input_is_valid(transaction: &Transaction, input_index: usize, key_index: usize) -> bool {
+        execute_input(
+            transaction,
+            input_index,
+            utxo(key_index).get_output().get_script_pubkey(),
+        )
+        .is_ok()
+    }
+
+    fn public_keys() -> Vec<Vec<u8>> {
+        PRIVATE_KEYS
+            .iter()
+            .map(|key| wallet::private_key_to_public_key(key).unwrap())
+            .collect()
+    }
+
+    fn extra_input() -> Input {
+        Input::create(&utxo(2))
+    }
+
+    // The block 170 transaction spends the coinbase of block 9, paid to a public key.
+    const BLOCK_9_SCRIPT_PUBKEY: &str = "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac";
+
+    #[test]
+    fn bitcoin_transaction_verifies() {
+        let transaction = Transaction::from_raw_hex(BITCOIN_RAW_TX).unwrap();
+        assert_eq!(transaction.bitcoin_txid(), BITCOIN_TXID);
+        assert_eq!(transaction.to_raw_hex(), BITCOIN_RAW_TX);
+        let script_pubkey = hex::decode(BLOCK_9_SCRIPT_PUBKEY).unwrap();
+        assert!(script::execute(
+            &transaction.tx_in[0].sig_script,
+            &script_pubkey,
+            &TxContext::new(&transaction, 0),
+        )
+        .is_ok());
+
+        let mut changed = transaction;
+        changed.tx_out[0].amount -= 1;
+        assert!(script::execute(
+            &changed.tx_in[0].sig_script,
+            &script_pubkey,
+            &TxContext::new(&changed, 0),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn re_encoded_signature_keeps_the_tx_id() {
+        let transaction = signed_transaction(0, SIGHASH_ALL);
+        let old_script = &transaction.tx_in[0].sig_script;
+        let mut instructions = Instructions::new(old_script);
+        let signature = match instructions.next() {
+            Some(Ok(Instruction::Push(signature))) => signature,
+            _ => panic!("signature script does not start with a push"),
+        };
+        // The same signature pushed with OP_PUSHDATA1 instead of a direct push
+        let mut sig_script = vec![OP_PUSHDATA1, signature.len() as u8];
+        sig_script.extend_from_slice(signature);
+        sig_script.extend_from_slice(&old_script[instructions.position()..]);
+
+        let mut re_encoded = transaction.clone();
+        re_encoded.tx_in[0].set_sig_script(sig_script);
+        assert_ne!(&re_encoded.tx_in[0].sig_script, old_script);
+        assert!(input_is_valid(&re_encoded, 0, 0));
+        assert_eq!(re_encoded.tx_id(), transaction.tx_id());
+        assert_ne!(re_encoded.hash(), transaction.hash());
+    }
+
+    #[test]
+    fn sighash_all_commits_to_all_inputs_and_outputs() {
+        let transaction = signed_transaction(0, SIGHASH_ALL);
+        assert!(input_is_valid(&transaction, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_out[1].amount = 1;
+        assert!(!input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_in[1].sequence = 0;
+        assert!(!input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction;
+        changed.tx_in.push(extra_input());
+        assert!(!input_is_valid(&changed, 0, 0));
+    }
+
+    #[test]
+    fn sighash_none_leaves_outputs_and_other_sequences_open() {
+        let transaction = signed_transaction(0, SIGHASH_NONE);
+        assert!(input_is_valid(&transaction, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_out = vec![Output::create(17, &[7; 20])];
+        changed.tx_in[1].sequence = 0;
+        assert!(input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_in[0].sequence = 0;
+        assert!(!input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_in[1] = extra_input();
+        assert!(!input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction;
+        changed.lock_time = 1;
+        assert!(!input_is_valid(&changed, 0, 0));
+    }
+
+    #[test]
+    fn sighash_single_commits_to_the_output_with_the_same_index() {
+        let transaction = signed_transaction(1, SIGHASH_SINGLE);
+        assert!(input_is_valid(&transaction, 1, 1));
+
+        // Outputs before the signed one only count, later ones are free
+        let mut changed = transaction.clone();
+        changed.tx_out[0] = Output::create(1, &[7; 20]);
+        changed.tx_out.push(Output::create(1, &[8; 20]));
+        changed.tx_in[0].sequence = 0;
+        assert!(input_is_valid(&changed, 1, 1));
+
+        let mut changed = transaction.clone();
+        changed.tx_out[1].amount = 1;
+        assert!(!input_is_valid(&changed, 1, 1));
+
+        let mut changed = transaction.clone();
+        changed.tx_out.remove(0);
+        assert!(!input_is_valid(&changed, 1, 1));
+
+        let mut changed = transaction;
+        changed.tx_in.push(extra_input());
+        assert!(!input_is_valid(&changed, 1, 1));
+    }
+
+    #[test]
+    fn sighash_single_needs_an_output_with_the_same_index() {
+        let mut transaction = unsigned_transaction();
+        transaction.tx_out.truncate(1);
+        assert!(transaction
+            .sign_input(1, &PRIVATE_KEYS[1], SIGHASH_SINGLE)
+            .is_err());
+    }
+
+    #[test]
+    fn sighash_anyonecanpay_commits_to_the_signed_input_alone() {
+        let transaction = signed_transaction(0, SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+        assert!(input_is_valid(&transaction, 0, 0));
+
+        // Other inputs can be added, removed or changed, even moving the signed input
+        let mut changed = transaction.clone();
+        changed.tx_in[1] = extra_input();
+        assert!(input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_in.truncate(1);
+        assert!(input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_in.insert(0, extra_input());
+        assert!(input_is_valid(&changed, 1, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_out[1].amount = 1;
+        assert!(!input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction;
+        changed.tx_in[0].sequence = 0;
+        assert!(!input_is_valid(&changed, 0, 0));
+    }
+
+    #[test]
+    fn sighash_single_anyonecanpay_allows_adding_inputs_and_outputs() {
+        let transaction = signed_transaction(0, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY);
+        assert!(input_is_valid(&transaction, 0, 0));
+
+        let mut changed = transaction.clone();
+        changed.tx_in.push(extra_input());
+        changed.tx_out[1].amount = 1;
+        changed.tx_out.push(Output::create(1, &[8; 20]));
+        assert!(input_is_valid(&changed, 0, 0));
+
+        let mut changed = transaction;
+        changed.tx_out[0].amount = 1;
+        assert!(!input_is_valid(&changed, 0, 0));
+    }
+
+    #[test]
+    fn sighash_type_is_committed_to() {
+        let mut transaction = signed_transaction(0, SIGHASH_ALL);
+        // Swap the sighash byte at the end of the signature push
+        let position = transaction.tx_in[0].sig_script[0] as usize;
+        transaction.tx_in[0].sig_script[position] = SIGHASH_NONE;
+        assert!(!input_is_valid(&transaction, 0, 0));
+    }
+
+    #[test]
+    fn multisig_signatures_must_follow_key_order() {
+        let script_pubkey = Output::create_multisig(10, 2, &public_keys())
+            .unwrap()
+            .get_script_pubkey()
+            .to_vec();
+        let transaction = spending_transaction(Output::new(10, script_pubkey.clone()));
+        let hash = transaction
+            .signature_hash(0, &script_pubkey, SIGHASH_ALL)
+            .unwrap();
+        let signatures: Vec<_> = PRIVATE_KEYS
+            .iter()
+            .map(|key| {
+                let (mut signature, _) = wallet::sign(&hash, key).unwrap();
+                signature.push(SIGHASH_ALL);
+                signature
+            })
+            .collect();
+        let with_sig_script = |dummy: u8, order: &[usize]| {
+            let mut sig_script = vec![dummy];
+            for index in order {
+                script::push_data(&mut sig_script, &signatures[*index]);
+            }
+            let mut transaction = transaction.clone();
+            transaction.tx_in[0].set_sig_script(sig_script);
+            transaction
+        };
+        assert!(execute_input(&with_sig_script(OP_0, &[0, 2]), 0, &script_pubkey).is_ok());
+        assert!(execute_input(&with_sig_script(OP_0, &[1, 2]), 0, &script_pubkey).is_ok());
+        for order in [[2, 0], [1, 1]].iter() {
+            assert_eq!(
+                error_message(execute_input(
+                    &with_sig_script(OP_0, order),
+                    0,
+                    &script_pubkey
+                )),
+                "Multisig signature verification failed"
+            );
+        }
+        assert_eq!(
+            error_message(execute_input(
+                &with_sig_script(OP_1, &[0, 2]),
+                0,
+                &script_pubkey
+            )),
+            "Multisig dummy element must be empty"
+        );
+        // Empty signatures fail softly, leaving false on the stack
+        let mut transaction = transaction.clone();
+        transaction.tx_in[0].set_sig_script(vec![OP_0, OP_0, OP_0]);
+        assert_eq!(
+            error_message(execute_input(&transaction, 0, &script_pubkey)),
+            "Script evaluated to false"
+        );
+    }
+
+    #[test]
+    fn multisig_co_signers_can_sign_in_any_order() {
+        let output = Output::create_multisig(10, 2, &public_keys()).unwrap();
+        let script_pubkey = output.get_script_pubkey().to_vec();
+        let mut transaction = spending_transaction(output);
+        transaction
+            .sign_multisig_input(0, &PRIVATE_KEYS[2], &script_pubkey, false, SIGHASH_ALL)
+            .unwrap();
+        assert!(execute_input(&transaction, 0, &script_pubkey).is_err());
+        transaction
+            .sign_multisig_input(0, &PRIVATE_KEYS[0], &script_pubkey, false, SIGHASH_ALL)
+            .unwrap();
+        assert!(execute_input(&transaction, 0, &script_pubkey).is_ok());
+        assert_eq!(
+            error_message(transaction.sign_multisig_input(
+                0,
+                &PRIVATE_KEYS[1],
+                &script_pubkey,
+                false,
+                SIGHASH_ALL
+            )),
+            "Input already has enough signatures"
+        );
+    }
+
+    #[test]
+    fn coinbase_outputs_are_spendable_once_mature() {
+        let coinbase = CoinBaseTransaction::new(&[5; 20], 5, 50);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_unspent(&coinbase, 5);
+        let coinbase_utxo = utxo_set
+            .get(&OutPoint::new(coinbase.tx_id(), 0))
+            .unwrap()
+            .clone();
+        let transaction = Transaction::new(
+            vec![Input::create(&coinbase_utxo)],
+            vec![Output::create(50, &[6; 20])],
+        );
+
+        assert_eq!(
+            error_message(transaction.check_coinbase_maturity(&utxo_set, 104, 100)),
+            "Transaction spends immature coinbase output"
+        );
+        assert!(transaction
+            .check_coinbase_maturity(&utxo_set, 105, 100)
+            .is_ok());
+
+        // Outputs of other transactions may be spent in the next block
+        let funding = Transaction::new(vec![], vec![Output::create(50, &[5; 20])]);
+        utxo_set.add_unspent(&funding, 5);
+        let utxo = utxo_set.get(&OutPoint::new(funding.tx_id(), 0)).unwrap();
+        let transaction = Transaction::new(
+            vec![Input::create(utxo)],
+            vec![Output::create(50, &[6; 20])],
+        );
+        assert!(transaction
+            .check_coinbase_maturity(&utxo_set, 6, 100)
+            .is_ok());
+    }
+
+    #[test]
+    fn lock_numbers_out_of_range_fail() {
+        let cases: [(&[u8], &str); 3] = [
+            (
+                &[0x00, 0x00, 0x00, 0x00, 0x01],
+                "Lock time in script out of range",
+            ),
+            (&[0x81], "Negative lock time in script"),
+            (
+                &[0x00, 0x00, 0x00, 0x00, 0x00, 0x01],
+                "Script number overflow",
+            ),
+        ];
+        for (operand, message) in cases.iter() {
+            for opcode in [OP_CHECKLOCKTIMEVERIFY, OP_CHECKSEQUENCEVERIFY].iter() {
+                let mut script_pubkey = vec![];
+                script::push_data(&mut script_pubkey, operand);
+                script_pubkey.push(*opcode);
+                let mut transaction = spending_transaction(Output::new(10, script_pubkey.clone()));
+                transaction.tx_in[0].set_sig_script(vec![]);
+                assert!(
+                    error_message(execute_input(&transaction, 0, &script_pubkey))
+                        .ends_with(message)
+                );
+            }
+        }
+    }
+
+    fn locked_transaction(lock_time: u32, sequence: u32) -> Transaction {
+        let mut transaction = unsigned_transaction();
+        transaction.lock_time = lock_time;
+        for input in &mut transaction.tx_in {
+            input.sequence = sequence;
+        }
+        transaction
+    }
+
+    #[test]
+    fn lock_time_is_final_once_passed() {
+        assert!(locked_transaction(0, 0).is_final(0, 0));
+        // Height locks need a later block, time locks a later median time
+        let transaction = locked_transaction(10, 0);
+        assert!(!transaction.is_final(10, u64::MAX));
+        assert!(transaction.is_final(11, 0));
+        let transaction = locked_transaction(LOCKTIME_THRESHOLD + 100, 0);
+        assert!(!transaction.is_final(u32::MAX, (LOCKTIME_THRESHOLD + 100) as u64));
+        assert!(transaction.is_final(0, (LOCKTIME_THRESHOLD + 101) as u64));
+        // Final sequences on every input opt out of the lock time
+        assert!(locked_transaction(10, SEQUENCE_FINAL).is_final(0, 0));
+        let mut transaction = locked_transaction(10, SEQUENCE_FINAL);
+        transaction.tx_in[1].sequence = 0;
+        assert!(!transaction.is_final(0, 0));
+
+        let mut transaction = unsigned_transaction();
+        transaction.set_lock_time(10);
+        assert!(!transaction.is_final(10, 0));
+    }
+
+    #[test]
+    fn check_lock_time_needs_the_same_kind_and_a_later_lock_time() {
+        let transaction = locked_transaction(100, 0);
+        assert!(transaction.check_lock_time(0, 0));
+        assert!(transaction.check_lock_time(0, 100));
+        assert!(!transaction.check_lock_time(0, 101));
+        assert!(!transaction.check_lock_time(0, LOCKTIME_THRESHOLD));
+        let transaction = locked_transaction(LOCKTIME_THRESHOLD + 100, 0);
+        assert!(transaction.check_lock_time(0, LOCKTIME_THRESHOLD + 100));
+        assert!(!transaction.check_lock_time(0, LOCKTIME_THRESHOLD + 101));
+        assert!(!transaction.check_lock_time(0, 100));
+        // A final input would let the transaction ignore its lock time
+        assert!(!locked_transaction(100, SEQUENCE_FINAL).check_lock_time(0, 100));
+    }
+
+    #[test]
+    fn check_sequence_compares_relative_lock_times() {
+        let by_height = locked_transaction(0, 10);
+        assert!(by_height.check_sequence(0, 10));
+        assert!(!by_height.check_sequence(0, 11));
+        assert!(!by_height.check_sequence(0, SEQUENCE_LOCKTIME_TYPE_FLAG | 1));
+        // Bits outside the type flag and the lock value are ignored
+        assert!(by_height.check_sequence(0, 1 << 30 | 10));
+        let by_time = locked_transaction(0, SEQUENCE_LOCKTIME_TYPE_FLAG | 10);
+        assert!(by_time.check_sequence(0, SEQUENCE_LOCKTIME_TYPE_FLAG | 10));
+        assert!(!by_time.check_sequence(0, SEQUENCE_LOCKTIME_TYPE_FLAG | 11));
+        assert!(!by_time.check_sequence(0, 10));
+        // The disable flag in the script always passes, on the input it always fails
+        assert!(by_height.check_sequence(0, SEQUENCE_LOCKTIME_DISABLE_FLAG | 0xffff));
+        let disabled = locked_transaction(0, SEQUENCE_LOCKTIME_DISABLE_FLAG | 10);
+        assert!(!disabled.check_sequence(0, 10));
+    }
+
+    // Spends an output created at utxo_height with the given sequence, after the
+    // blocks up to spend_height.
+    fn check_relative_lock(
+        utxo_height: u32,
+        sequence: u32,
+        spend_height: usize,
+    ) -> Result<(), RitCoinErrror<'static>> {
+        let funding = Transaction::new(vec![], vec![Output::create(10, &[1; 20])]);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_unspent(&funding, utxo_height);
+        let utxo = utxo_set.get(&OutPoint::new(funding.tx_id(), 0)).unwrap();
+        let mut transaction =
+            Transaction::new(vec![Input::create(utxo)], vec![Output::create(9, &[5; 20])]);
+        transaction.tx_in[0].sequence = sequence;
+        let blocks =
+            vec![Block::new(vec![0; serializer::HASH_SIZE], vec![], 0x207f_ffff); spend_height];
+        transaction.check_locks(&utxo_set, &blocks)
+    }
+
+    #[test]
+    fn check_locks_enforces_relative_lock_times() {
+        let locked = "Transaction input is locked by its relative lock time";
+        assert!(check_relative_lock(1, 3, 4).is_ok());
+        assert_eq!(error_message(check_relative_lock(1, 4, 4)), locked);
+        assert!(check_relative_lock(1, SEQUENCE_LOCKTIME_DISABLE_FLAG | 4, 4).is_ok());
+        // Time locks count from the median time past before the output's block, 512
+        // seconds per unit. The test blocks are all stamped now.
+        let by_time = SEQUENCE_LOCKTIME_TYPE_FLAG | 1;
+        assert!(check_relative_lock(0, by_time, 4).is_ok());
+        assert_eq!(error_message(check_relative_lock(4, by_time, 4)), locked);
+        assert!(check_relative_lock(4, SEQUENCE_LOCKTIME_TYPE_FLAG, 4).is_ok());
+    }
+
+    #[test]
+    fn check_locks_rejects_transactions_not_final_in_the_next_block() {
+        let blocks = vec![Block::new(vec![0; serializer::HASH_SIZE], vec![], 0x207f_ffff); 4];
+        let utxo_set = UtxoSet::new();
+        let mut transaction = unsigned_transaction();
+        transaction.set_lock_time(3);
+        assert!(transaction.check_locks(&utxo_set, &blocks).is_ok());
+        transaction.set_lock_time(4);
+        assert_eq!(
+            error_message(transaction.check_locks(&utxo_set, &blocks)),
+            "Transaction is not final, its lock time is not reached"
+        );
+    }
+
+    #[test]
+    fn unknown_inputs_are_reported() {
+        let known = utxo(0);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_unspent(
+            &Transaction::new(vec![], vec![known.get_output().clone()]),
+            1,
+        );
+        let known = utxo_set.by_pkhash(known.get_output().get_pkhash().unwrap())[0];
+        let mut transaction = Transaction::new(
+            vec![Input::create(known), Input::create(&utxo(1))],
+            vec![Output::create(8, &[5; 20])],
+        );
+        transaction
+            .sign_input(0, &PRIVATE_KEYS[0], SIGHASH_ALL)
+            .unwrap();
+        transaction
+            .sign_input(1, &PRIVATE_KEYS[1], SIGHASH_ALL)
+            .unwrap();
+        let missing = vec![utxo(1).get_outpoint()];
+        for result in [
+            transaction.validate(&utxo_set),
+            transaction.verify(&utxo_set),
+        ]
+        .iter()
+        {
+            match result {
+                Err(RitCoinErrror::MissingInputs(outpoints)) => assert_eq!(outpoints, &missing),
+                result => panic!("expected missing inputs, got {:?}", result),
+            }
+        }
+        assert_eq!(
+            missing[0].to_string(),
+            format!("{}:0", hex::encode(vec![1; serializer::HASH_SIZE]))
+        );
+
+        let mut transaction = Transaction::new(
+            vec![Input::create(known)],
+            vec![Output::create(8, &[5; 20])],
+        );
+        transaction.sign(&PRIVATE_KEYS[0], SIGHASH_ALL).unwrap();
+        assert!(transaction.validate(&utxo_set).is_ok());
+    }
+
+    #[test]
+    fn data_outputs_are_limited_in_number_and_size() {
+        let max_size = ChainParams::main().max_data_carrier_size;
+        let with_outputs = |outputs: Vec<Output>| {
+            let mut transaction = unsigned_transaction();
+            transaction.tx_out.extend(outputs);
+            transaction.check_data_outputs(max_size)
+        };
+        assert!(with_outputs(vec![]).is_ok());
+        assert!(with_outputs(vec![Output::create_data(&vec![1; max_size])]).is_ok());
+        assert!(with_outputs(vec![Output::create_data(&[])]).is_ok());
+        assert_eq!(
+            error_message(with_outputs(vec![Output::create_data(&vec![
+                1;
+                max_size + 1
+            ])])),
+            "Data output exceeds the maximum payload size"
+        );
+        assert_eq!(
+            error_message(with_outputs(vec![
+                Output::create_data(&[1]),
+                Output::create_data(&[2])
+            ])),
+            "Transaction has more than one data output"
+        );
+        assert_eq!(
+            error_message(with_outputs(vec![Output::new(
+                0,
+                vec![OP_RETURN, OP_1, OP_1]
+            )])),
+            "Unspendable output is not a data output"
+        );
+    }
+
+    const HTLC_SECRET: [u8; 32] = [7; 32];
+    const HTLC_LOCK_TIME: u32 = 100;
+
+    // HTLC paying the first key with the secret and refunding to the second, and an
+    // unsigned spend of its P2SH output.
+    fn htlc_spend() -> (Vec<u8>, Vec<u8>, Transaction) {
+        let pkhashes: Vec<_> = public_keys()
+            .iter()
+            .map(|key| wallet::pk_hash_from_public_key(key))
+            .collect();
+        let redeem_script = Output::create_htlc(
+            0,
+            &sha256(&HTLC_SECRET),
+            &pkhashes[0],
+            HTLC_LOCK_TIME,
+            &pkhashes[1],
+        )
+        .get_script_pubkey()
+        .to_vec();
+        let output = Output::create_p2sh(10, &hash160(&redeem_script));
+        let script_pubkey = output.get_script_pubkey().to_vec();
+        let utxo = Utxo::new(vec![9; serializer::HASH_SIZE], 0, output, 1, false);
+        let transaction = Transaction::new(
+            vec![Input::create_p2sh(&utxo, &redeem_script)],
+            vec![Output::create(9, &[5; 20])],
+        );
+        (redeem_script, script_pubkey, transaction)
+    }
+
+    #[test]
+    fn htlc_script_is_recognised() {
+        let (redeem_script, _, _) = htlc_spend();
+        let (secret_hash, recipient_pkhash, lock_time, refund_pkhash) =
+            script::get_htlc(&redeem_script).unwrap();
+        assert_eq!(secret_hash, sha256(&HTLC_SECRET).as_slice());
+        assert_eq!(
+            recipient_pkhash,
+            wallet::pk_hash_from_public_key(&public_keys()[0]).as_slice()
+        );
+        assert_eq!(lock_time, HTLC_LOCK_TIME);
+        assert_eq!(
+            refund_pkhash,
+            wallet::pk_hash_from_public_key(&public_keys()[1]).as_slice()
+        );
+        assert!(script::get_htlc(&redeem_script[1..]).is_none());
+    }
+
+    #[test]
+    fn htlc_is_claimed_with_the_secret_and_the_recipient_key() {
+        let (_, script_pubkey, unsigned) = htlc_spend();
+        let claim = |key_index: usize, secret: &[u8]| {
+            let mut transaction = unsigned.clone();
+            transaction
+                .sign_htlc_input(0, &PRIVATE_KEYS[key_index], Some(secret), SIGHASH_ALL)
+                .unwrap();
+            transaction
+        };
+        let transaction = claim(0, &HTLC_SECRET);
+        assert!(execute_input(&transaction, 0, &script_pubkey).is_ok());
+        // Whoever watches the claim learns the secret
+        assert_eq!(
+            script::get_htlc_secret(transaction.tx_in[0].get_sig_script()),
+            Some(&HTLC_SECRET[..])
+        );
+        for transaction in [
+            claim(0, &[8; 32]),
+            claim(1, &HTLC_SECRET),
+            claim(0, &[7; 31]),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_message(execute_input(transaction, 0, &script_pubkey)),
+                "Script verification failed"
+            );
+        }
+        assert!(script::get_htlc_secret(claim(0, &[8; 32]).tx_in[0].get_sig_script()).is_none());
+    }
+
+    #[test]
+    fn htlc_is_refunded_once_the_lock_time_is_reached() {
+        let (_, script_pubkey, unsigned) = htlc_spend();
+        let refund = |key_index: usize, lock_time: u32| {
+            let mut transaction = unsigned.clone();
+            transaction.set_lock_time(lock_time);
+            transaction
+                .sign_htlc_input(0, &PRIVATE_KEYS[key_index], None, SIGHASH_ALL)
+                .unwrap();
+            transaction
+        };
+        let transaction = refund(1, HTLC_LOCK_TIME);
+        assert!(execute_input(&transaction, 0, &script_pubkey).is_ok());
+        assert!(script::get_htlc_secret(transaction.tx_in[0].get_sig_script()).is_none());
+        assert_eq!(
+            error_message(execute_input(&refund(0, HTLC_LOCK_TIME), 0, &script_pubkey)),
+            "Script verification failed"
+        );
+        // The refund branch fails until the transaction lock time reaches the HTLC's
+        for lock_time in [0, HTLC_LOCK_TIME - 1].iter() {
+            assert_eq!(
+                error_message(execute_input(&refund(1, *lock_time), 0, &script_pubkey)),
+                "Lock time requirement not satisfied"
+            );
+        }
+    }
+}
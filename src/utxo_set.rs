@@ -1,24 +1,42 @@
-use crate::transaction::{Input, Output, Transaction};
+use crate::errors::*;
+use crate::transaction::{Input, OutPoint, Output, Transaction};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
 
-const UTXO_SET_PATH: &str = "data/utxo_set.txt";
+pub const UTXO_SET_FILE: &str = "utxo_set.txt";
+
+// Spent utxos of every transaction in a block, in transaction order.
+pub type BlockUndo = Vec<Vec<Utxo>>;
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct Utxo {
     tx_id: Vec<u8>,
     index: u32,
     output: Output,
+    height: u32,
+    is_coinbase: bool,
 }
 
 impl Utxo {
-    pub fn new(tx_id: Vec<u8>, index: u32, output: Output) -> Self {
+    pub fn new(tx_id: Vec<u8>, index: u32, output: Output, height: u32, is_coinbase: bool) -> Self {
         Self {
             tx_id,
             index,
             output,
+            height,
+            is_coinbase,
         }
     }
 
+    pub fn is_mature(&self, spend_height: u32, coinbase_maturity: u32) -> bool {
+        !self.is_coinbase || spend_height >= self.height + coinbase_maturity
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.height
+    }
+
     pub fn get_output(&self) -> &Output {
         &self.output
     }
@@ -31,76 +49,169 @@ impl Utxo {
         self.index
     }
 
+    pub fn get_outpoint(&self) -> OutPoint {
+        OutPoint::new(self.tx_id.clone(), self.index)
+    }
+
     pub fn is_locked(&self, prepared_transactions: &[Transaction]) -> bool {
         prepared_transactions.iter().any(|tx| {
             tx.get_tx_in()
                 .iter()
-                .any(|tx_in| self.tx_id == tx_in.get_previous_output().get_tx_id())
+                .any(|tx_in| tx_in.get_previous_output().get() == (&self.tx_id, self.index))
         })
     }
 }
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct UtxoSet {
+
+#[derive(Serialize, Deserialize)]
+struct UtxoSetData {
+    tip: Vec<u8>,
     utxos: Vec<Utxo>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "UtxoSetData", into = "UtxoSetData")]
+pub struct UtxoSet {
+    tip: Vec<u8>,
+    utxos: HashMap<OutPoint, Utxo>,
+    by_pkhash: HashMap<Vec<u8>, HashSet<OutPoint>>,
+}
+
+impl From<UtxoSetData> for UtxoSet {
+    fn from(data: UtxoSetData) -> Self {
+        let mut utxo_set = Self::new();
+        utxo_set.tip = data.tip;
+        data.utxos
+            .into_iter()
+            .for_each(|utxo| utxo_set.insert(utxo));
+        utxo_set
+    }
+}
+
+impl From<UtxoSet> for UtxoSetData {
+    fn from(utxo_set: UtxoSet) -> Self {
+        Self {
+            tip: utxo_set.tip,
+            utxos: utxo_set.utxos.into_values().collect(),
+        }
+    }
+}
+
 impl UtxoSet {
     pub fn new() -> Self {
-        Self { utxos: vec![] }
+        Self {
+            tip: vec![],
+            utxos: HashMap::new(),
+            by_pkhash: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, RitCoinErrror<'static>> {
+        Ok(bincode::deserialize(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), RitCoinErrror<'static>> {
+        let tmp_path = path.to_owned() + ".tmp";
+        fs::write(&tmp_path, bincode::serialize(self)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn get_tip(&self) -> &[u8] {
+        &self.tip
+    }
+
+    pub fn set_tip(&mut self, tip: Vec<u8>) {
+        self.tip = tip;
+    }
+
+    fn insert(&mut self, utxo: Utxo) {
+        let outpoint = utxo.get_outpoint();
+        if let Some(pkhash) = utxo.get_output().get_pkhash() {
+            self.by_pkhash
+                .entry(pkhash.to_vec())
+                .or_default()
+                .insert(outpoint.clone());
+        }
+        self.utxos.insert(outpoint, utxo);
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Utxo> {
+        self.utxos.get(outpoint)
     }
 
     pub fn by_pkhash(&self, pkhash: &[u8]) -> Vec<&Utxo> {
-        self.utxos
-            .iter()
-            .filter(|utxo| {
-                utxo.get_output()
-                    .get_script_pubkey()
-                    .windows(pkhash.len())
-                    .any(|hash| hash == pkhash)
-            })
-            .collect()
+        match self.by_pkhash.get(pkhash) {
+            Some(outpoints) => outpoints
+                .iter()
+                .filter_map(|outpoint| self.utxos.get(outpoint))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_validation_data(&self, outpoint: &OutPoint) -> Option<(&[u8], u64)> {
+        self.get(outpoint).map(|utxo| {
+            (
+                utxo.get_output().get_script_pubkey(),
+                utxo.get_output().get_amount(),
+            )
+        })
     }
 
-    pub fn get_validation_data<'a>(
-        utxos: &'a [&Utxo],
-        tx_id: &[u8],
-        index: u32,
-    ) -> Option<(&'a [u8], u64)> {
-        for utxo in utxos {
-            if utxo.get_tx_id() == tx_id && index == index {
-                return Some((
-                    utxo.get_output().get_script_pubkey(),
-                    utxo.get_output().get_amount(),
-                ));
+    pub fn remove(&mut self, outpoint: &OutPoint) -> Option<Utxo> {
+        let utxo = self.utxos.remove(outpoint)?;
+        if let Some(pkhash) = utxo.get_output().get_pkhash() {
+            if let Some(outpoints) = self.by_pkhash.get_mut(pkhash) {
+                outpoints.remove(outpoint);
+                if outpoints.is_empty() {
+                    self.by_pkhash.remove(pkhash);
+                }
             }
         }
-        None
+        Some(utxo)
     }
 
-    pub fn remove(&mut self, input: &Input) {
-        self.utxos
+    pub fn remove_used(&mut self, tx_in: &[Input]) -> Vec<Utxo> {
+        tx_in
             .iter()
-            .position(|utxo| {
-                (utxo.get_tx_id(), utxo.get_index()) == input.get_previous_output().get()
-            })
-            .map(|i| self.utxos.remove(i));
+            .filter_map(|input| self.remove(input.get_previous_output()))
+            .collect()
     }
 
-    pub fn remove_used(&mut self, tx_in: &[Input]) {
-        tx_in.iter().for_each(|input| self.remove(input))
+    pub fn add_unspent(&mut self, transaction: &Transaction, height: u32) {
+        let tx_id = transaction.tx_id();
+        let is_coinbase = transaction.is_coinbase();
+        for (index, output) in transaction.get_tx_out().iter().enumerate() {
+            if output.is_unspendable() {
+                continue;
+            }
+            let utxo = Utxo::new(
+                tx_id.clone(),
+                index as u32,
+                output.clone(),
+                height,
+                is_coinbase,
+            );
+            self.insert(utxo);
+        }
     }
 
-    pub fn add_unspent(&mut self, tx_out: &[Output], tx_id: Vec<u8>) {
-        tx_out.iter().enumerate().for_each(|(index, output)| {
-            let utxo = Utxo::new(tx_id.clone(), index as u32, output.clone());
-            self.utxos.push(utxo);
-        })
+    pub fn recalculate_utxos(&mut self, transactions: &[Transaction], height: u32) -> BlockUndo {
+        let mut undo = Vec::with_capacity(transactions.len());
+        for transaction in transactions {
+            undo.push(self.remove_used(transaction.get_tx_in()));
+            self.add_unspent(transaction, height);
+        }
+        undo
     }
 
-    pub fn recalculate_utxos(&mut self, transactions: &[Transaction]) {
-        for transaction in transactions {
-            self.remove_used(transaction.get_tx_in());
-            self.add_unspent(transaction.get_tx_out(), transaction.hash());
+    pub fn revert_utxos(&mut self, transactions: &[Transaction], undo: BlockUndo) {
+        for (transaction, spent) in transactions.iter().zip(undo).rev() {
+            let tx_id = transaction.tx_id();
+            for index in 0..transaction.get_tx_out().len() {
+                self.remove(&OutPoint::new(tx_id.clone(), index as u32));
+            }
+            spent.into_iter().for_each(|utxo| self.insert(utxo));
         }
     }
 
@@ -109,11 +220,16 @@ impl UtxoSet {
         sender_pkhash: &[u8],
         amount: u64,
         prepared_transactions: &[Transaction],
+        spend_height: u32,
+        coinbase_maturity: u32,
     ) -> Option<Vec<&Utxo>> {
         let mut utxos: Vec<_> = self
             .by_pkhash(sender_pkhash)
             .into_iter()
-            .filter(|utxo| !utxo.is_locked(prepared_transactions))
+            .filter(|utxo| {
+                !utxo.is_locked(prepared_transactions)
+                    && utxo.is_mature(spend_height, coinbase_maturity)
+            })
             .collect();
         println!("{:?}", utxos);
         let exact_utxo = utxos
@@ -128,11 +244,12 @@ impl UtxoSet {
                 .filter(|utxo| utxo.get_output().get_amount() < amount)
                 .map(|utxo| *utxo)
                 .collect::<Vec<_>>();
-            if exact_utxos
-                .iter()
-                .map(|utxo| utxo.get_output().get_amount())
-                .sum::<u64>()
-                == amount
+            if !exact_utxos.is_empty()
+                && exact_utxos
+                    .iter()
+                    .map(|utxo| utxo.get_output().get_amount())
+                    .sum::<u64>()
+                    == amount
             {
                 return Some(exact_utxos);
             } else {
@@ -169,3 +286,118 @@ impl UtxoSet {
             .fold(0, |acc, utxo| acc + utxo.get_output().get_amount())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_params;
+
+    // Pays two outputs to the pubkey hash [1; 20] and one to [2; 20].
+    fn funding_transaction() -> Transaction {
+        Transaction::new(
+            vec![],
+            vec![
+                Output::create(10, &[1; 20]),
+                Output::create(20, &[1; 20]),
+                Output::create(30, &[2; 20]),
+            ],
+        )
+    }
+
+    fn spending_transaction(
+        utxo_set: &UtxoSet,
+        outpoints: &[OutPoint],
+        pkhash: &[u8],
+    ) -> Transaction {
+        let utxos: Vec<_> = outpoints
+            .iter()
+            .map(|outpoint| utxo_set.get(outpoint).unwrap())
+            .collect();
+        Transaction::new(
+            Input::create_inputs(&utxos),
+            vec![Output::create(UtxoSet::get_total_amount(&utxos), pkhash)],
+        )
+    }
+
+    // The pubkey hash index holds exactly the outpoints of the utxos paying each hash.
+    fn assert_index_is_consistent(utxo_set: &UtxoSet) {
+        let mut by_pkhash: HashMap<Vec<u8>, HashSet<OutPoint>> = HashMap::new();
+        for (outpoint, utxo) in &utxo_set.utxos {
+            if let Some(pkhash) = utxo.get_output().get_pkhash() {
+                by_pkhash
+                    .entry(pkhash.to_vec())
+                    .or_default()
+                    .insert(outpoint.clone());
+            }
+        }
+        assert_eq!(utxo_set.by_pkhash, by_pkhash);
+    }
+
+    #[test]
+    fn saved_utxo_set_loads_with_its_tip_and_index() {
+        let params = test_params("utxo-save-load");
+        let path = params.data_path(UTXO_SET_FILE);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_unspent(&funding_transaction(), 1);
+        utxo_set.set_tip(vec![5; 32]);
+        utxo_set.save(&path).unwrap();
+
+        let loaded = UtxoSet::load(&path).unwrap();
+        assert_eq!(loaded, utxo_set);
+        assert_eq!(loaded.get_tip(), &[5; 32][..]);
+        assert_eq!(loaded.by_pkhash(&[1; 20]).len(), 2);
+        assert_index_is_consistent(&loaded);
+    }
+
+    #[test]
+    fn pkhash_index_follows_spends_and_reverts() {
+        let funding = funding_transaction();
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.recalculate_utxos(std::slice::from_ref(&funding), 1);
+        let funded = utxo_set.clone();
+        assert_eq!(utxo_set.by_pkhash(&[1; 20]).len(), 2);
+        assert_index_is_consistent(&utxo_set);
+
+        let outpoint = |index| OutPoint::new(funding.tx_id(), index);
+        let first_spend = spending_transaction(&utxo_set, &[outpoint(0), outpoint(2)], &[2; 20]);
+        let first_undo = utxo_set.recalculate_utxos(std::slice::from_ref(&first_spend), 2);
+        assert_eq!(utxo_set.by_pkhash(&[1; 20]).len(), 1);
+        assert_eq!(
+            utxo_set.by_pkhash(&[2; 20])[0].get_output().get_amount(),
+            40
+        );
+        assert_index_is_consistent(&utxo_set);
+
+        // Spending the last output of a hash drops the hash from the index
+        let second_spend = spending_transaction(&utxo_set, &[outpoint(1)], &[3; 20]);
+        let second_undo = utxo_set.recalculate_utxos(std::slice::from_ref(&second_spend), 3);
+        assert!(utxo_set.by_pkhash(&[1; 20]).is_empty());
+        assert!(!utxo_set.by_pkhash.contains_key(&[1; 20][..]));
+        assert_index_is_consistent(&utxo_set);
+
+        utxo_set.revert_utxos(std::slice::from_ref(&second_spend), second_undo);
+        assert_index_is_consistent(&utxo_set);
+        utxo_set.revert_utxos(std::slice::from_ref(&first_spend), first_undo);
+        assert_eq!(utxo_set, funded);
+        assert_index_is_consistent(&utxo_set);
+    }
+
+    #[test]
+    fn unspendable_outputs_stay_out_of_the_utxo_set() {
+        let transaction = Transaction::new(
+            vec![],
+            vec![
+                Output::create(10, &[1; 20]),
+                Output::create_data(b"anchored"),
+                Output::new(0, vec![0; 10_001]),
+            ],
+        );
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.add_unspent(&transaction, 1);
+        let tx_id = transaction.tx_id();
+        assert!(utxo_set.get(&OutPoint::new(tx_id.clone(), 0)).is_some());
+        assert!(utxo_set.get(&OutPoint::new(tx_id.clone(), 1)).is_none());
+        assert!(utxo_set.get(&OutPoint::new(tx_id, 2)).is_none());
+        assert_eq!(utxo_set.utxos.len(), 1);
+    }
+}
@@ -1,20 +1,27 @@
+use crate::chain_params::ChainParams;
 use crate::errors::*;
 use crate::hash::*;
+use crate::script;
 use crate::wallet_cli::*;
-use secp256k1::{rand::rngs::OsRng, Message, PublicKey, Secp256k1, SecretKey, Signature};
+use secp256k1::{
+    rand::rngs::OsRng, rand::RngCore, Message, PublicKey, Secp256k1, SecretKey, Signature,
+};
 use sha2::{Digest, Sha256};
 
-const NETWORK_ID: u8 = 0x00;
-const VERSION_NUMBER: u8 = 0x80;
-
-pub fn private_key_to_wif_from_file(path: &str) -> Result<String, RitCoinErrror<'static>> {
+pub fn private_key_to_wif_from_file(
+    path: &str,
+    params: &ChainParams,
+) -> Result<String, RitCoinErrror<'static>> {
     let private_key = fs::read_to_string(path)?;
-    private_key_to_wif(&private_key)
+    private_key_to_wif(&private_key, params)
 }
 
-pub fn wif_to_private_key_from_file(path: &str) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+pub fn wif_to_private_key_from_file(
+    path: &str,
+    params: &ChainParams,
+) -> Result<Vec<u8>, RitCoinErrror<'static>> {
     let private_key_wif = fs::read_to_string(path)?;
-    Ok(wif_to_private_key(&private_key_wif)?)
+    wif_to_private_key(&private_key_wif, params)
 }
 
 fn get_checksum(key: &[u8]) -> Result<Vec<u8>, RitCoinErrror<'static>> {
@@ -28,16 +35,27 @@ fn get_checksum(key: &[u8]) -> Result<Vec<u8>, RitCoinErrror<'static>> {
     Ok(hash2[..4].to_vec())
 }
 
-pub fn private_key_to_wif(key: &str) -> Result<String, RitCoinErrror<'static>> {
+pub fn private_key_to_wif(
+    key: &str,
+    params: &ChainParams,
+) -> Result<String, RitCoinErrror<'static>> {
     let mut key = hex::decode(key)?;
-    key.insert(0, VERSION_NUMBER);
+    key.insert(0, params.wif_prefix);
     let checksum = get_checksum(&key)?;
     key.extend_from_slice(&checksum);
     Ok(bs58::encode(key).into_string())
 }
 
-pub fn wif_to_private_key(key: &str) -> Result<Vec<u8>, bs58::decode::Error> {
+pub fn wif_to_private_key(
+    key: &str,
+    params: &ChainParams,
+) -> Result<Vec<u8>, RitCoinErrror<'static>> {
     let key = bs58::decode(key).into_vec()?;
+    if key.len() < 5 || key[0] != params.wif_prefix {
+        return Err(RitCoinErrror::from(
+            "Private key does not belong to the selected network",
+        ));
+    }
     let (private_key, _) = key.split_at(key.len() - 4);
     Ok(private_key[1..].to_vec())
 }
@@ -48,6 +66,12 @@ pub fn generate_ecdsa_key_pair() -> (SecretKey, PublicKey) {
     secp.generate_keypair(&mut rng)
 }
 
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; script::HTLC_SECRET_SIZE];
+    OsRng::new().expect("OsRng").fill_bytes(&mut secret);
+    secret
+}
+
 pub fn private_key_to_public_key(private_key: &[u8]) -> Result<Vec<u8>, secp256k1::Error> {
     let secp = Secp256k1::new();
     let secret_key = SecretKey::from_slice(private_key)?;
@@ -60,14 +84,25 @@ pub fn sign(hash: &[u8], private_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), secp2
     let secret_key = SecretKey::from_slice(private_key)?;
     let public_key = PublicKey::from_secret_key(&secp, &secret_key);
     let hash = Message::from_slice(hash)?;
-    let sig = secp.sign(&hash, &secret_key).serialize_der();
-    Ok((sig.to_vec(), get_uncompressed_pub_key(&public_key)))
+    let mut sig = secp.sign(&hash, &secret_key);
+    sig.normalize_s();
+    Ok((
+        sig.serialize_der().to_vec(),
+        get_uncompressed_pub_key(&public_key),
+    ))
 }
 
 pub fn verify(hash: &[u8], signature: &[u8], public_key: &[u8]) -> Result<(), secp256k1::Error> {
     let secp = Secp256k1::new();
     let hash = Message::from_slice(hash)?;
     let signature = Signature::from_der(signature)?;
+    // Only the low S form of a signature is accepted, its high S twin would be a
+    // second valid encoding of the same signature
+    let mut normalized_signature = signature;
+    normalized_signature.normalize_s();
+    if normalized_signature != signature {
+        return Err(secp256k1::Error::IncorrectSignature);
+    }
     let public_key = PublicKey::from_slice(public_key)?;
     secp.verify(&hash, &signature, &public_key)
 }
@@ -76,20 +111,151 @@ pub fn get_uncompressed_pub_key(public_key: &PublicKey) -> Vec<u8> {
     public_key.serialize_uncompressed().to_vec()
 }
 
-pub fn get_address(public_key: &[u8]) -> Result<String, RitCoinErrror<'static>> {
-    let mut encrypted_pub_key = pk_hash_from_public_key(public_key);
-    encrypted_pub_key.insert(0, NETWORK_ID);
-    let checksum = get_checksum(&encrypted_pub_key)?;
-    encrypted_pub_key.extend_from_slice(&checksum);
-    Ok(bs58::encode(encrypted_pub_key).into_string())
+fn encode_address(hash: &[u8], prefix: u8) -> Result<String, RitCoinErrror<'static>> {
+    let mut address = hash.to_vec();
+    address.insert(0, prefix);
+    let checksum = get_checksum(&address)?;
+    address.extend_from_slice(&checksum);
+    Ok(bs58::encode(address).into_string())
+}
+
+pub fn get_address(
+    public_key: &[u8],
+    params: &ChainParams,
+) -> Result<String, RitCoinErrror<'static>> {
+    pkhash_to_address(&pk_hash_from_public_key(public_key), params)
+}
+
+pub fn pkhash_to_address(
+    pkhash: &[u8],
+    params: &ChainParams,
+) -> Result<String, RitCoinErrror<'static>> {
+    encode_address(pkhash, params.pubkey_address_prefix)
+}
+
+// P2SH address of a redeem script, it commits to the hash160 of the script.
+pub fn get_script_address(
+    redeem_script: &[u8],
+    params: &ChainParams,
+) -> Result<String, RitCoinErrror<'static>> {
+    script_hash_to_address(&hash160(redeem_script), params)
+}
+
+pub fn script_hash_to_address(
+    script_hash: &[u8],
+    params: &ChainParams,
+) -> Result<String, RitCoinErrror<'static>> {
+    encode_address(script_hash, params.script_address_prefix)
 }
 
 pub fn pk_hash_from_public_key(public_key: &[u8]) -> Vec<u8> {
-    let sha256_hash = sha256(public_key);
-    ripemd160(&sha256_hash)
+    hash160(public_key)
 }
 
-pub fn address_to_pkhash(address: &str) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+// Splits an address into its version prefix and hash, only 20 byte hashes with a matching
+// checksum and a prefix of the selected network are accepted. A mistyped address would
+// otherwise pay to a script nobody can spend.
+pub fn decode_address(
+    address: &str,
+    params: &ChainParams,
+) -> Result<(u8, Vec<u8>), RitCoinErrror<'static>> {
     let decoded_addr = bs58::decode(address).into_vec()?;
-    Ok(decoded_addr[1..decoded_addr.len() - 4].to_vec())
+    if decoded_addr.len() != 1 + 20 + 4 {
+        return Err(RitCoinErrror::from("Address does not hold a 20 byte hash"));
+    }
+    let (payload, checksum) = decoded_addr.split_at(decoded_addr.len() - 4);
+    if get_checksum(payload)? != checksum {
+        return Err(RitCoinErrror::from("Address checksum does not match"));
+    }
+    if payload[0] != params.pubkey_address_prefix && payload[0] != params.script_address_prefix {
+        return Err(RitCoinErrror::from(
+            "Address does not belong to the selected network",
+        ));
+    }
+    Ok((payload[0], payload[1..].to_vec()))
+}
+
+pub fn address_to_pkhash(
+    address: &str,
+    params: &ChainParams,
+) -> Result<Vec<u8>, RitCoinErrror<'static>> {
+    match decode_address(address, params)? {
+        (prefix, pkhash) if prefix == params.pubkey_address_prefix => Ok(pkhash),
+        _ => Err(RitCoinErrror::from(
+            "Address is not a public key hash address",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pow::U256;
+    use crate::test_utils::error_message;
+
+    // Order of the secp256k1 group.
+    const CURVE_ORDER: &str = "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+
+    #[test]
+    fn high_s_signatures_are_rejected() {
+        let hash = [3; 32];
+        let (signature, public_key) = sign(&hash, &[1; 32]).unwrap();
+        assert!(verify(&hash, &signature, &public_key).is_ok());
+
+        // Negating S gives the other valid signature of the same message
+        let mut compact = Signature::from_der(&signature)
+            .unwrap()
+            .serialize_compact()
+            .to_vec();
+        let order = U256::from_be_bytes(&hex::decode(CURVE_ORDER).unwrap());
+        let high_s = order - U256::from_be_bytes(&compact[32..]);
+        compact[32..].copy_from_slice(&high_s.to_be_bytes());
+        let high_s_signature = Signature::from_compact(&compact)
+            .unwrap()
+            .serialize_der()
+            .to_vec();
+        assert_ne!(high_s_signature, signature);
+        assert_eq!(
+            verify(&hash, &high_s_signature, &public_key),
+            Err(secp256k1::Error::IncorrectSignature)
+        );
+    }
+
+    #[test]
+    fn decode_address_checks_length_checksum_and_network() {
+        let params = ChainParams::main();
+        let address = pkhash_to_address(&[1; 20], &params).unwrap();
+        assert_eq!(
+            decode_address(&address, &params).unwrap(),
+            (params.pubkey_address_prefix, vec![1; 20])
+        );
+        let script_address = script_hash_to_address(&[2; 20], &params).unwrap();
+        assert_eq!(
+            decode_address(&script_address, &params).unwrap(),
+            (params.script_address_prefix, vec![2; 20])
+        );
+
+        let mut mistyped = bs58::decode(&address).into_vec().unwrap();
+        mistyped[5] ^= 1;
+        assert_eq!(
+            error_message(decode_address(
+                &bs58::encode(mistyped).into_string(),
+                &params
+            )),
+            "Address checksum does not match"
+        );
+        for hash in [&[1; 19][..], &[1; 21][..]].iter() {
+            assert_eq!(
+                error_message(decode_address(
+                    &encode_address(hash, params.pubkey_address_prefix).unwrap(),
+                    &params
+                )),
+                "Address does not hold a 20 byte hash"
+            );
+        }
+        assert_eq!(
+            error_message(decode_address(&address, &ChainParams::test())),
+            "Address does not belong to the selected network"
+        );
+    }
 }
@@ -1,8 +1,13 @@
-use crate::cli::{ADDRESS_PATH, PRIVATE_KEY_PATH};
+use crate::block::Block;
+use crate::chain_params::ChainParams;
+use crate::cli::{ADDRESS_FILE, PRIVATE_KEY_FILE};
 use crate::errors::*;
+use crate::hash::{hash160, sha256};
 use crate::pending_pool;
-use crate::serializer;
-use crate::server::{BROADCAST_RESOURCE, DEFAULT_ADDRESS};
+use crate::script;
+use crate::script_asm;
+use crate::serializer::{self, HASH_SIZE};
+use crate::server::BROADCAST_RESOURCE;
 use crate::transaction::*;
 use crate::utxo_set::*;
 use crate::wallet;
@@ -21,10 +26,10 @@ pub fn write_pub_address_to_file(
     Ok(())
 }
 
-pub fn new(path_to_pub_address: &str) -> Result<(), RitCoinErrror<'static>> {
+pub fn new(path_to_pub_address: &str, params: &ChainParams) -> Result<(), RitCoinErrror<'static>> {
     let (private_key, public_key) = wallet::generate_ecdsa_key_pair();
-    let pub_address = wallet::get_address(&public_key.serialize_uncompressed())?;
-    let private_key_wif = wallet::private_key_to_wif(&format!("{}", private_key))?;
+    let pub_address = wallet::get_address(&public_key.serialize_uncompressed(), params)?;
+    let private_key_wif = wallet::private_key_to_wif(&format!("{}", private_key), params)?;
     println!("{}", private_key_wif);
     write_pub_address_to_file(&pub_address, path_to_pub_address)
 }
@@ -32,25 +37,262 @@ pub fn new(path_to_pub_address: &str) -> Result<(), RitCoinErrror<'static>> {
 pub fn import(
     path_to_private_key: &str,
     path_to_pub_address: &str,
+    params: &ChainParams,
 ) -> Result<(), RitCoinErrror<'static>> {
-    let private_key = wallet::wif_to_private_key_from_file(path_to_private_key)?;
+    let private_key = wallet::wif_to_private_key_from_file(path_to_private_key, params)?;
     let public_key = wallet::private_key_to_public_key(&private_key)?;
-    let pub_address = wallet::get_address(&public_key)?;
+    let pub_address = wallet::get_address(&public_key, params)?;
     println!("{:?}", private_key);
     write_pub_address_to_file(&pub_address, path_to_pub_address)
 }
 
+pub fn public_key(params: &ChainParams) -> Result<(), RitCoinErrror<'static>> {
+    let private_key_wif = fs::read_to_string(params.data_path(PRIVATE_KEY_FILE))?;
+    let private_key = wallet::wif_to_private_key(&private_key_wif, params)?;
+    println!(
+        "{}",
+        hex::encode(wallet::private_key_to_public_key(&private_key)?)
+    );
+    Ok(())
+}
+
 pub fn send(
     receiver_address: &str,
     amount: u64,
+    fee: u64,
+    lock_time: u32,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let payment = Output::create_for_address(amount, receiver_address, &ritcoin_state.params)?;
+    send_output(
+        payment,
+        fee,
+        lock_time,
+        prepared_transactions,
+        ritcoin_state,
+    )
+}
+
+pub fn send_multisig(
+    required: usize,
+    public_keys: &[Vec<u8>],
+    amount: u64,
+    fee: u64,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let payment = Output::create_multisig(amount, required, public_keys)?;
+    send_output(payment, fee, 0, prepared_transactions, ritcoin_state)
+}
+
+// Prints the P2SH address of an m-of-n multisig script and the script itself, which
+// is needed to spend from the address.
+pub fn multisig_address(
+    required: usize,
+    public_keys: &[Vec<u8>],
+    params: &ChainParams,
+) -> Result<(), RitCoinErrror<'static>> {
+    let redeem_output = Output::create_multisig(0, required, public_keys)?;
+    let redeem_script = redeem_output.get_script_pubkey();
+    println!("{}", wallet::get_script_address(redeem_script, params)?);
+    println!("{}", hex::encode(redeem_script));
+    Ok(())
+}
+
+// Embeds data in an OP_RETURN output of a transaction funded by the wallet, anchoring it
+// in the chain at the time the transaction is mined.
+pub fn anchor(
+    data: &[u8],
+    fee: u64,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    if data.len() > ritcoin_state.params.max_data_carrier_size {
+        return Err(RitCoinErrror::from("Data exceeds the maximum payload size"));
+    }
+    let payment = Output::create_data(data);
+    send_output(payment, fee, 0, prepared_transactions, ritcoin_state)
+}
+
+// Anchors the sha256 hash of a file, timestamping the document without revealing it.
+pub fn anchor_file(
+    path: &str,
+    fee: u64,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let file_hash = sha256(&fs::read(path)?);
+    println!("{}", hex::encode(&file_hash));
+    anchor(&file_hash, fee, prepared_transactions, ritcoin_state)
+}
+
+// Prints a new swap secret and its hash. Only the hash is given to the counterparty,
+// the secret is revealed on chain when the HTLC is claimed.
+pub fn htlc_secret() {
+    let secret = wallet::generate_secret();
+    println!("{}", hex::encode(&secret));
+    println!("{}", hex::encode(sha256(&secret)));
+}
+
+// Locks amount in a P2SH HTLC the recipient can claim with the secret, the wallet can
+// refund it once lock_time is reached. Prints the HTLC address and redeem script,
+// which the recipient needs to claim, before the funding transaction.
+pub fn htlc_create(
+    recipient_address: &str,
+    secret_hash: &[u8],
+    lock_time: u32,
+    amount: u64,
+    fee: u64,
     prepared_transactions: &mut Vec<Vec<u8>>,
     ritcoin_state: Arc<RitCoinState>,
 ) -> Result<(), RitCoinErrror<'static>> {
-    let sender_adress = fs::read_to_string(ADDRESS_PATH)?;
-    let private_key_wif = fs::read_to_string(PRIVATE_KEY_PATH)?;
-    let private_key = wallet::wif_to_private_key(&private_key_wif)?;
-    let sender_pkhash = wallet::address_to_pkhash(&sender_adress)?;
-    let receiver_pkhash = wallet::address_to_pkhash(&receiver_address)?;
+    let params = &ritcoin_state.params;
+    if secret_hash.len() != HASH_SIZE {
+        return Err(RitCoinErrror::from("Secret hash must be 32 bytes"));
+    }
+    if lock_time == 0 {
+        return Err(RitCoinErrror::from("HTLC lock time must not be zero"));
+    }
+    let recipient_pkhash = wallet::address_to_pkhash(recipient_address, params)?;
+    let sender_address = fs::read_to_string(params.data_path(ADDRESS_FILE))?;
+    let refund_pkhash = wallet::address_to_pkhash(&sender_address, params)?;
+    let redeem_output =
+        Output::create_htlc(0, secret_hash, &recipient_pkhash, lock_time, &refund_pkhash);
+    let redeem_script = redeem_output.get_script_pubkey();
+    println!("{}", wallet::get_script_address(redeem_script, params)?);
+    println!("{}", hex::encode(redeem_script));
+    let payment = Output::create_p2sh(amount, &hash160(redeem_script));
+    send_output(payment, fee, 0, prepared_transactions, ritcoin_state)
+}
+
+// Spends an HTLC output to the wallet, claiming it with the secret or, without one,
+// refunding it after its lock time.
+pub fn spend_htlc(
+    outpoint: &str,
+    redeem_script: &[u8],
+    secret: Option<Vec<u8>>,
+    fee: u64,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let params = &ritcoin_state.params;
+    let outpoint = parse_outpoint(outpoint)?;
+    let receiver_address = fs::read_to_string(params.data_path(ADDRESS_FILE))?;
+    let receiver_pkhash = wallet::address_to_pkhash(&receiver_address, params)?;
+    let private_key_wif = fs::read_to_string(params.data_path(PRIVATE_KEY_FILE))?;
+    let private_key = wallet::wif_to_private_key(&private_key_wif, params)?;
+    let (secret_hash, recipient_pkhash, lock_time, refund_pkhash) =
+        match script::get_htlc(redeem_script) {
+            Some(htlc) => htlc,
+            None => return Err(RitCoinErrror::from("Redeem script is not an HTLC")),
+        };
+    match &secret {
+        Some(secret) if sha256(secret) != secret_hash => {
+            return Err(RitCoinErrror::from(
+                "Secret does not match the HTLC secret hash",
+            ))
+        }
+        Some(_) if recipient_pkhash != &receiver_pkhash[..] => {
+            return Err(RitCoinErrror::from("HTLC does not pay to this wallet"))
+        }
+        None if refund_pkhash != &receiver_pkhash[..] => {
+            return Err(RitCoinErrror::from("HTLC does not refund to this wallet"))
+        }
+        _ => (),
+    }
+    if let Ok(blockchain_state) = ritcoin_state.blockchain.lock() {
+        let utxo_set = blockchain_state.get_utxos_ref();
+        let utxo = match utxo_set.get(&outpoint) {
+            Some(utxo) => utxo,
+            None => return Err(RitCoinErrror::from("Unspent output not found")),
+        };
+        if utxo.get_output().get_script_hash() != Some(&hash160(redeem_script)[..]) {
+            return Err(RitCoinErrror::from(
+                "Output does not pay to the HTLC redeem script",
+            ));
+        }
+        let utxo_amount = utxo.get_output().get_amount();
+        if fee >= utxo_amount {
+            return Err(RitCoinErrror::from("HTLC amount does not cover the fee"));
+        }
+        let input = Input::create_p2sh(utxo, redeem_script);
+        let output = Output::create(utxo_amount - fee, &receiver_pkhash);
+        let mut transaction = Transaction::new(vec![input], vec![output]);
+        if secret.is_none() {
+            transaction.set_lock_time(lock_time);
+            let blocks = blockchain_state.get_blocks();
+            if !transaction.is_final(blocks.len() as u32, Block::median_time_past(blocks)) {
+                return Err(RitCoinErrror::from("HTLC lock time is not reached yet"));
+            }
+        }
+        transaction.sign_htlc_input(0, &private_key, secret.as_deref(), SIGHASH_ALL)?;
+        transaction.validate(utxo_set)?;
+        let serialized = serializer::serialize(&transaction);
+        println!("{:?}", serialized);
+        prepared_transactions.push(serialized);
+        Ok(())
+    } else {
+        Err(RitCoinErrror::from(
+            "Error, when accessing blockchain state occured",
+        ))
+    }
+}
+
+// Finds the transaction that claimed an HTLC output and prints the secret it revealed,
+// which claims the other HTLC of the swap.
+pub fn htlc_extract(
+    outpoint: &str,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let outpoint = parse_outpoint(outpoint)?;
+    if let Ok(blockchain_state) = ritcoin_state.blockchain.lock() {
+        for block in blockchain_state.get_blocks().iter().rev() {
+            for transaction in block.get_deserialized_transactions()? {
+                for input in transaction.get_tx_in() {
+                    if *input.get_previous_output() == outpoint {
+                        return match script::get_htlc_secret(input.get_sig_script()) {
+                            Some(secret) => {
+                                println!("{}", hex::encode(secret));
+                                Ok(())
+                            }
+                            None => Err(RitCoinErrror::from(
+                                "Output was not claimed with an HTLC secret",
+                            )),
+                        };
+                    }
+                }
+            }
+        }
+        Err(RitCoinErrror::from("Output has not been spent"))
+    } else {
+        Err(RitCoinErrror::from(
+            "Error, when accessing blockchain state occured",
+        ))
+    }
+}
+
+fn parse_outpoint(outpoint: &str) -> Result<OutPoint, RitCoinErrror<'static>> {
+    match outpoint.split(':').collect::<Vec<&str>>().as_slice() {
+        [tx_id, index] => Ok(OutPoint::new(hex::decode(tx_id)?, index.parse::<u32>()?)),
+        _ => Err(RitCoinErrror::from("Outpoint must be <tx id>:<index>")),
+    }
+}
+
+// Funds the payment output from the wallet's utxos, the change goes back to the wallet.
+// The payment is the first output, its outpoint is printed after the transaction.
+fn send_output(
+    payment: Output,
+    fee: u64,
+    lock_time: u32,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let params = &ritcoin_state.params;
+    let sender_adress = fs::read_to_string(params.data_path(ADDRESS_FILE))?;
+    let private_key_wif = fs::read_to_string(params.data_path(PRIVATE_KEY_FILE))?;
+    let private_key = wallet::wif_to_private_key(&private_key_wif, params)?;
+    let sender_pkhash = wallet::address_to_pkhash(&sender_adress, params)?;
     let mut prepared_transactions_deserialized = Vec::with_capacity(prepared_transactions.len());
     for tx in prepared_transactions.iter() {
         if !tx.is_empty() {
@@ -58,21 +300,31 @@ pub fn send(
             prepared_transactions_deserialized.push(deserialized_tx)
         }
     }
+    let total = sum_amounts([payment.get_amount(), fee])?;
     if let Ok(blockchain_state) = ritcoin_state.blockchain.lock() {
         if let Some(used_utxos) = blockchain_state.get_utxos_ref().get_used_utxos(
             &sender_pkhash,
-            amount,
+            total,
             &prepared_transactions_deserialized,
+            blockchain_state.len() as u32,
+            params.coinbase_maturity,
         ) {
             let inputs = Input::create_inputs(&used_utxos);
             let utxo_total = UtxoSet::get_total_amount(&used_utxos);
-            let outputs =
-                Output::create_single(amount, utxo_total, &sender_pkhash, &receiver_pkhash);
+            let outputs = Output::create_single(payment, utxo_total - fee, &sender_pkhash);
             let mut transaction = Transaction::new(inputs, outputs);
-            transaction.sign(&private_key)?;
-            transaction.validate(&used_utxos)?;
-            let serialized = serializer::serialize(&transaction)?;
+            transaction.set_lock_time(lock_time);
+            transaction.sign(&private_key, SIGHASH_ALL)?;
+            transaction.validate(blockchain_state.get_utxos_ref())?;
+            // Nodes only accept transactions final in the next block, a time locked one
+            // stays prepared until it can be broadcast
+            let blocks = blockchain_state.get_blocks();
+            if !transaction.is_final(blocks.len() as u32, Block::median_time_past(blocks)) {
+                println!("Transaction is time locked, broadcast it once its lock time is reached");
+            }
+            let serialized = serializer::serialize(&transaction);
             println!("{:?}", serialized);
+            println!("{}:0", hex::encode(transaction.tx_id()));
             prepared_transactions.push(serialized);
             Ok(())
         } else {
@@ -87,6 +339,113 @@ pub fn send(
     }
 }
 
+// Creates an unsigned spend of a multisig output given as <tx id>:<index>, the rest
+// of its amount after the fee is paid back to the same script. Outputs paying to a
+// P2SH multisig address need the redeem script. The raw transaction is passed between
+// co-signers with cosign.
+pub fn spend_multisig(
+    outpoint: &str,
+    receiver_address: &str,
+    amount: u64,
+    fee: u64,
+    redeem_script: Option<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let outpoint = parse_outpoint(outpoint)?;
+    let payment = Output::create_for_address(amount, receiver_address, &ritcoin_state.params)?;
+    if let Ok(blockchain_state) = ritcoin_state.blockchain.lock() {
+        let utxo = match blockchain_state.get_utxos_ref().get(&outpoint) {
+            Some(utxo) => utxo,
+            None => return Err(RitCoinErrror::from("Unspent output not found")),
+        };
+        let input = match (utxo.get_output().get_script_hash(), &redeem_script) {
+            (Some(script_hash), Some(redeem_script))
+                if hash160(redeem_script) == script_hash
+                    && script::get_multisig(redeem_script).is_some() =>
+            {
+                Input::create_p2sh(utxo, redeem_script)
+            }
+            (None, None) if utxo.get_output().get_multisig().is_some() => Input::create(utxo),
+            _ => {
+                return Err(RitCoinErrror::from(
+                    "Output is not a multisig output or the redeem script does not match",
+                ))
+            }
+        };
+        let utxo_amount = utxo.get_output().get_amount();
+        if sum_amounts([amount, fee])? > utxo_amount {
+            return Err(RitCoinErrror::from(
+                "Multisig output amount is less than amount and fee",
+            ));
+        }
+        let mut outputs = vec![payment];
+        if utxo_amount - amount - fee != 0 {
+            outputs.push(Output::new(
+                utxo_amount - amount - fee,
+                utxo.get_output().get_script_pubkey().to_vec(),
+            ));
+        }
+        let transaction = Transaction::new(vec![input], outputs);
+        println!("{}", transaction.to_raw_hex());
+        Ok(())
+    } else {
+        Err(RitCoinErrror::from(
+            "Error, when accessing blockchain state occured",
+        ))
+    }
+}
+
+// Signs every multisig input of a raw transaction the wallet key belongs to. Once
+// enough co-signers have signed it can be broadcast.
+pub fn cosign(
+    raw_tx: &str,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    ritcoin_state: Arc<RitCoinState>,
+) -> Result<(), RitCoinErrror<'static>> {
+    let params = &ritcoin_state.params;
+    let private_key_wif = fs::read_to_string(params.data_path(PRIVATE_KEY_FILE))?;
+    let private_key = wallet::wif_to_private_key(&private_key_wif, params)?;
+    let mut transaction = Transaction::from_raw_hex(raw_tx)?;
+    if let Ok(blockchain_state) = ritcoin_state.blockchain.lock() {
+        let utxo_set = blockchain_state.get_utxos_ref();
+        let mut signed = 0;
+        for i in 0..transaction.get_tx_in().len() {
+            let input = &transaction.get_tx_in()[i];
+            let output = match utxo_set.get(input.get_previous_output()) {
+                Some(utxo) => utxo.get_output(),
+                None => continue,
+            };
+            let (script_code, p2sh) = match output.get_script_hash() {
+                Some(script_hash) => match input.get_redeem_script(script_hash) {
+                    Some(redeem_script) => (redeem_script, true),
+                    None => continue,
+                },
+                None => (output.get_script_pubkey().to_vec(), false),
+            };
+            if script::get_multisig(&script_code).is_none() {
+                continue;
+            }
+            transaction.sign_multisig_input(i, &private_key, &script_code, p2sh, SIGHASH_ALL)?;
+            signed += 1;
+        }
+        if signed == 0 {
+            return Err(RitCoinErrror::from("Transaction has no multisig inputs"));
+        }
+        if transaction.validate(utxo_set).is_ok() {
+            println!("Transaction is fully signed");
+        }
+        println!("{}", transaction.to_raw_hex());
+        let serialized = serializer::serialize(&transaction);
+        println!("{:?}", serialized);
+        prepared_transactions.push(serialized);
+        Ok(())
+    } else {
+        Err(RitCoinErrror::from(
+            "Error, when accessing blockchain state occured",
+        ))
+    }
+}
+
 pub fn unlock_all(prepared_transactions: &mut Vec<Vec<u8>>) {
     *prepared_transactions = vec![];
 }
@@ -98,23 +457,25 @@ pub fn unlock(serialized_tx: &str, prepared_transactions: &mut Vec<Vec<u8>>) {
         .map(|i| prepared_transactions.remove(i));
 }
 
+// The transaction stays prepared until a node accepts it, so a rejected one, like a
+// time locked transaction sent too early, can be broadcast again.
 pub fn broadcast(
     serialized_tx: &str,
     prepared_transactions: &mut Vec<Vec<u8>>,
-    testnet_option: bool,
+    node_address: &str,
 ) -> Result<(), RitCoinErrror<'static>> {
     println!("{:?}", serialized_tx);
-    let tx = prepared_transactions
+    let position = prepared_transactions
         .iter()
-        .position(|tx| *tx == pending_pool::tx_str_to_vec(serialized_tx))
-        .map(|i| prepared_transactions.remove(i));
-    if let Some(tx) = &tx {
+        .position(|tx| *tx == pending_pool::tx_str_to_vec(serialized_tx));
+    if let Some(position) = position {
         let client = Client::new();
-        let url = DEFAULT_ADDRESS.to_owned() + BROADCAST_RESOURCE;
+        let url = node_address.to_owned() + BROADCAST_RESOURCE;
         let mut map = HashMap::new();
-        map.insert("tx", tx);
+        map.insert("tx", &prepared_transactions[position]);
         let mut res = client.post(&url).json(&map).send()?;
         if res.status() == StatusCode::OK {
+            prepared_transactions.remove(position);
             println!("{}", res.text()?);
             Ok(())
         } else {
@@ -125,6 +486,45 @@ pub fn broadcast(
     }
 }
 
+pub fn export_raw(serialized_tx: &str) -> Result<(), RitCoinErrror<'static>> {
+    let transaction: Transaction =
+        serializer::deserialize(&pending_pool::tx_str_to_vec(serialized_tx))?;
+    println!("{}", transaction.bitcoin_txid());
+    println!("{}", transaction.to_raw_hex());
+    Ok(())
+}
+
+// Imported transactions are added to the prepared ones so they can be broadcast.
+pub fn import_raw(
+    raw_tx: &str,
+    prepared_transactions: &mut Vec<Vec<u8>>,
+    params: &ChainParams,
+) -> Result<(), RitCoinErrror<'static>> {
+    let transaction = Transaction::from_raw_hex(raw_tx)?;
+    println!("{}", transaction.bitcoin_txid());
+    println!("{:#}", transaction.to_json(params));
+    let serialized = serializer::serialize(&transaction);
+    println!("{:?}", serialized);
+    prepared_transactions.push(serialized);
+    Ok(())
+}
+
+pub fn decode_raw(raw_tx: &str, params: &ChainParams) -> Result<(), RitCoinErrror<'static>> {
+    let transaction = Transaction::from_raw_hex(raw_tx)?;
+    println!("{:#}", transaction.to_json(params));
+    Ok(())
+}
+
+pub fn disassemble(script_hex: &str) -> Result<(), RitCoinErrror<'static>> {
+    println!("{}", script_asm::to_asm(&hex::decode(script_hex)?));
+    Ok(())
+}
+
+pub fn assemble(asm: &str) -> Result<(), RitCoinErrror<'static>> {
+    println!("{}", hex::encode(script_asm::from_asm(asm)?));
+    Ok(())
+}
+
 pub fn balance(
     address: &str,
     ritcoin_state: Arc<RitCoinState>,